@@ -1,12 +1,18 @@
 #!/bin/sh
 
-if [ "$#" -ne "2" ] ; then
-    echo "usage: $0 <grant|revoke> <client>"
+if [ "$#" -lt "2" ] ; then
+    echo "usage: $0 <grant|revoke> <client> [service]"
 fi                
 
 SERVER_INTF=igb0
 ACTION=$1
 CLIENT_ADDR=$2
+SERVICE=${3:-ssh}
+
+if [ "$SERVICE" != 'ssh' ] ; then
+    echo "unsupported service: $SERVICE"
+    exit 1
+fi
 
 SERVER_ADDR=$(ifconfig $SERVER_INTF | grep 'inet ' | awk '{print $2}')
 
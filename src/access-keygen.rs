@@ -1,4 +1,3 @@
-use std::error::Error;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::PathBuf;
@@ -12,22 +11,13 @@ fn write_keypair(path_str: &str, keypair: &Keypair) -> Result<(), AccessError> {
     let path = PathBuf::from(path_str);
 
     let mut file = File::create(&path).map_err(|e| {
-        AccessError::FileError(format!(
-            "couldn't create {}: {}",
-            path.display(),
-            e.description()
-        ))
+        AccessError::FileError(format!("couldn't create {}: {}", path.display(), e))
     })?;
 
-    let yaml = serde_yaml::to_string(&keypair).map_err(|e| AccessError::SerializeError(e))?;
+    let yaml = serde_yaml::to_string(&keypair).map_err(AccessError::SerializeError)?;
 
-    file.write_all(yaml.as_bytes()).map_err(|e| {
-        AccessError::FileError(format!(
-            "couldn't write to {}: {}",
-            path.display(),
-            e.description()
-        ))
-    })
+    file.write_all(yaml.as_bytes())
+        .map_err(|e| AccessError::FileError(format!("couldn't write to {}: {}", path.display(), e)))
 }
 
 fn main() {
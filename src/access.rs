@@ -3,38 +3,67 @@ use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::str::FromStr;
 use std::time::Duration;
 
+use access::config::DEFAULT_SERVICE;
 use access::err::AccessError;
 use access::keys::{ClientKeyData, KeyDataReader};
-use access::packet;
+use access::logger::{self, LogTarget};
+use access::packet::{self, Channel};
 use access::req::{ReqData, SessReq, REQ_PORT};
 use access::resp::SessResp;
 use access::state::{ClientState, StateManager};
-use clap::{crate_authors, crate_version, App, Arg};
+use clap::{crate_authors, crate_version, App, AppSettings, Arg, SubCommand};
 use futures::{Future, Sink, Stream};
-use sodiumoxide::crypto::box_::{Nonce, NONCEBYTES};
-use sodiumoxide::randombytes::randombytes;
+use log::LevelFilter;
 use tokio_core::net::{UdpCodec, UdpSocket};
 use tokio_core::reactor::{Core, Timeout};
 
+const DEFAULT_TIMEOUT: &str = "5";
+
+enum ReqType {
+    TimedAccess(Option<u64>),
+    Revoke,
+    Status,
+}
+
 struct ClientCodec {
     state: ClientState,
-    key_data: ClientKeyData,
+    channel: Channel,
 }
 
 impl ClientCodec {
     fn new(state_filename: &str, key_data_filename: &str) -> Result<Self, AccessError> {
-        let state = ClientState::read(state_filename)?;
+        let state = ClientState::read_or_new(state_filename)?;
         let key_data = ClientKeyData::read(key_data_filename)?;
-        Ok(ClientCodec { state, key_data })
+        let channel = Channel::new(&key_data.secret, &key_data.peer_public);
+        Ok(ClientCodec { state, channel })
     }
 }
 
 impl UdpCodec for ClientCodec {
     type In = ();
-    type Out = (SocketAddr, IpAddr);
+    type Out = (SocketAddr, String, ReqData, Option<u64>);
 
     fn decode(&mut self, addr: &SocketAddr, buf: &[u8]) -> io::Result<Self::In> {
-        match packet::open(buf, &self.key_data.secret, &self.key_data.peer_public) {
+        match packet::version(buf) {
+            Some(version) if version != packet::PROTOCOL_VERSION => {
+                println!(
+                    "{}: server uses protocol version {}, but this client uses version {}",
+                    addr,
+                    version,
+                    packet::PROTOCOL_VERSION
+                );
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        // Servers that predate the packet header answer without one.
+        let format = if self.channel.is_hinted(buf) {
+            packet::Format::Header
+        } else {
+            packet::Format::Legacy
+        };
+        match self.channel.open(buf, format) {
             Ok(resp_packet) => {
                 match SessResp::from_msg(&resp_packet) {
                     Ok(recv_resp) => println!("{}: {}", addr, recv_resp),
@@ -46,23 +75,19 @@ impl UdpCodec for ClientCodec {
         Ok(())
     }
 
-    fn encode(&mut self, (remote_addr, client_addr): Self::Out, into: &mut Vec<u8>) -> SocketAddr {
+    fn encode(
+        &mut self,
+        (remote_addr, service, req_data, duration): Self::Out,
+        into: &mut Vec<u8>,
+    ) -> SocketAddr {
         self.state.cur_req_id += 1;
-        let nonce: Nonce = Nonce::from_slice(&randombytes(NONCEBYTES)).unwrap();
-        into.extend(&nonce[..]);
 
-        match SessReq::new(self.state.cur_req_id, ReqData::TimedAccess(client_addr)).to_msg() {
+        match SessReq::new(self.state.cur_req_id, &service, req_data, duration).to_msg() {
             Ok(msg) => {
-                let encrypted_req_packet = packet::create(
-                    &msg,
-                    &nonce,
-                    &self.key_data.secret,
-                    &self.key_data.peer_public,
-                );
                 if let Err(e) = self.state.write() {
                     println!("state file write failed: {}", e)
                 }
-                into.extend(encrypted_req_packet);
+                into.extend(self.channel.seal(&msg, packet::Format::Header));
             }
             Err(e) => println!("request creation failed: {}", e),
         }
@@ -70,8 +95,12 @@ impl UdpCodec for ClientCodec {
     }
 }
 
-fn get_remote_addr(remote_str: &str, prefer_ipv4: bool) -> Result<SocketAddr, AccessError> {
-    let mut addrs = format!("{}:{}", remote_str, REQ_PORT)
+fn get_remote_addr(
+    remote_str: &str,
+    port: u16,
+    prefer_ipv4: bool,
+) -> Result<SocketAddr, AccessError> {
+    let mut addrs = format!("{}:{}", remote_str, port)
         .to_socket_addrs()
         .map_err(AccessError::IoError)?;
 
@@ -80,7 +109,7 @@ fn get_remote_addr(remote_str: &str, prefer_ipv4: bool) -> Result<SocketAddr, Ac
             .find(|addr| addr.is_ipv4())
             .ok_or(AccessError::NoIpv4Addr)?
     } else {
-        addrs.nth(0).unwrap()
+        addrs.next().unwrap()
     };
 
     Ok(remote_addr)
@@ -95,7 +124,21 @@ fn get_bind_addr_for_remote(remote_addr: &SocketAddr) -> Result<SocketAddr, Acce
         return Err(AccessError::NoIpv4Addr);
     };
 
-    Ok(bind_addr_str.to_socket_addrs().unwrap().nth(0).unwrap())
+    Ok(bind_addr_str.to_socket_addrs().unwrap().next().unwrap())
+}
+
+fn is_seconds(value: String) -> Result<(), String> {
+    value
+        .parse::<u64>()
+        .map(|_| ())
+        .map_err(|e| format!("{}: {}", value, e))
+}
+
+fn is_port(value: String) -> Result<(), String> {
+    value
+        .parse::<u16>()
+        .map(|_| ())
+        .map_err(|e| format!("{}: {}", value, e))
 }
 
 fn get_client_addr(client_addr_str: &str) -> Result<IpAddr, AccessError> {
@@ -106,25 +149,31 @@ fn get_client_addr(client_addr_str: &str) -> Result<IpAddr, AccessError> {
 fn run(
     state_filename: &str,
     key_data_filename: &str,
-    remote_str: &str,
-    prefer_ipv4: bool,
+    remote_addr: SocketAddr,
     client_addr_str: &str,
+    service: &str,
+    req_type: ReqType,
+    timeout: u64,
 ) -> Result<(), AccessError> {
     let mut core = Core::new().map_err(AccessError::IoError)?;
     let handle = core.handle();
 
-    let remote_addr = get_remote_addr(remote_str, prefer_ipv4)?;
     let bind_addr = get_bind_addr_for_remote(&remote_addr)?;
     let client_addr = get_client_addr(client_addr_str)?;
+    let (req_data, duration) = match req_type {
+        ReqType::TimedAccess(duration) => (ReqData::TimedAccess(client_addr), duration),
+        ReqType::Revoke => (ReqData::Revoke(client_addr), None),
+        ReqType::Status => (ReqData::Status(client_addr), None),
+    };
     let codec = ClientCodec::new(state_filename, key_data_filename)?;
     let sock = UdpSocket::bind(&bind_addr, &handle).map_err(AccessError::IoError)?;
     let (framed_tx, framed_rx) = sock.framed(codec).split();
 
     let send_req = framed_tx
-        .send((remote_addr, client_addr))
+        .send((remote_addr, service.to_string(), req_data, duration))
         .and_then(|_| framed_rx.take(1).into_future().map_err(|(e, _)| e))
         .select2(
-            Timeout::new(Duration::from_secs(5), &handle)
+            Timeout::new(Duration::from_secs(timeout), &handle)
                 .unwrap()
                 .then(|t| {
                     println!("no response from {}", remote_addr);
@@ -137,19 +186,26 @@ fn run(
 }
 
 fn main() {
+    // Only warnings from the library, such as a missing state file, are logged.
+    if let Err(e) = logger::init("access", LogTarget::Stdout, LevelFilter::Warn) {
+        eprintln!("couldn't set up logging: {}", e);
+    }
     let default_state_filename =
         format!("{}/.access/state.yaml", dirs::home_dir().unwrap().display());
     let default_key_data_filename = format!(
         "{}/.access/keydata.yaml",
         dirs::home_dir().unwrap().display()
     );
+    let default_port = REQ_PORT.to_string();
 
     let matches = App::new("access")
         .version(crate_version!())
         .author(crate_authors!())
         .about("Sends access request to host")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("address")
+                .global(true)
                 .empty_values(false)
                 .short("a")
                 .long("addr")
@@ -158,6 +214,7 @@ fn main() {
         )
         .arg(
             Arg::with_name("state-file")
+                .global(true)
                 .empty_values(false)
                 .short("s")
                 .long("state-file")
@@ -166,34 +223,110 @@ fn main() {
         )
         .arg(
             Arg::with_name("key-data-file")
+                .global(true)
                 .empty_values(false)
                 .short("k")
                 .long("key-data-file")
                 .default_value(&default_key_data_filename)
                 .help("Path to key data file"),
         )
+        .arg(
+            Arg::with_name("service")
+                .global(true)
+                .empty_values(false)
+                .short("S")
+                .long("service")
+                .default_value(DEFAULT_SERVICE)
+                .help("Service to access"),
+        )
+        .arg(
+            Arg::with_name("port")
+                .global(true)
+                .empty_values(false)
+                .short("p")
+                .long("port")
+                .default_value(&default_port)
+                .validator(is_port)
+                .help("UDP port of the accessd server"),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .global(true)
+                .empty_values(false)
+                .short("t")
+                .long("timeout")
+                .default_value(DEFAULT_TIMEOUT)
+                .validator(is_seconds)
+                .help("Seconds to wait for the server's response"),
+        )
         .arg(
             Arg::with_name("prefer-ipv4")
+                .global(true)
                 .short("4")
                 .long("prefer-ipv4")
                 .help("Prefer IPv4 address"),
         )
+        .arg(
+            Arg::with_name("duration")
+                .empty_values(false)
+                .short("d")
+                .long("duration")
+                .validator(is_seconds)
+                .help("Requested session duration in seconds, limited by the server"),
+        )
+        .arg(
+            Arg::with_name("revoke")
+                .short("r")
+                .long("revoke")
+                .help("Revoke access instead of requesting it"),
+        )
         .arg(
             Arg::with_name("HOST")
                 .required(true)
                 .help("Remote host to access"),
         )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("Shows the status of the session on host")
+                .arg(
+                    Arg::with_name("HOST")
+                        .required(true)
+                        .help("Remote host to query"),
+                ),
+        )
         .get_matches();
 
+    let (args, req_type) = match matches.subcommand_matches("status") {
+        Some(status_matches) => (status_matches, ReqType::Status),
+        None if matches.is_present("revoke") => (&matches, ReqType::Revoke),
+        None => (
+            &matches,
+            ReqType::TimedAccess(
+                matches
+                    .value_of("duration")
+                    .map(|duration| duration.parse::<u64>().unwrap()),
+            ),
+        ),
+    };
+
     match sodiumoxide::init() {
         Ok(()) => {
-            if let Err(e) = run(
-                matches.value_of("state-file").unwrap(),
-                matches.value_of("key-data-file").unwrap(),
-                matches.value_of("HOST").unwrap(),
-                matches.is_present("prefer-ipv4"),
-                matches.value_of("address").unwrap(),
-            ) {
+            if let Err(e) = get_remote_addr(
+                args.value_of("HOST").unwrap(),
+                args.value_of("port").unwrap().parse::<u16>().unwrap(),
+                args.is_present("prefer-ipv4"),
+            )
+            .and_then(|remote_addr| {
+                run(
+                    args.value_of("state-file").unwrap(),
+                    args.value_of("key-data-file").unwrap(),
+                    remote_addr,
+                    args.value_of("address").unwrap(),
+                    args.value_of("service").unwrap(),
+                    req_type,
+                    args.value_of("timeout").unwrap().parse::<u64>().unwrap(),
+                )
+            }) {
                 println!("failed: {}", e);
             }
         }
@@ -0,0 +1,328 @@
+use std::io::prelude::*;
+use std::net::{IpAddr, Shutdown};
+use std::os::unix::net::UnixStream;
+use std::str::FromStr;
+
+use access::clock::unix_now;
+use access::config::{ServerConfig, DEFAULT_CONFIG_FILENAME, DEFAULT_SERVICE};
+use access::control::{ControlRequest, ControlResponse, SessionInfo};
+use access::err::AccessError;
+use access::keys::{
+    self, KeyDataReader, KeyPolicy, PeerKey, ServerKeyData, DEFAULT_KEYDATA_FILENAME,
+};
+use access::logger::{self, LogTarget};
+use access::state::{ServerState, StateManager, DEFAULT_STATE_FILENAME};
+use clap::{crate_authors, crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
+use log::LevelFilter;
+
+/// Sends one command to the server. The socket is taken from `--socket`, or
+/// else from the server's configuration file.
+fn control(matches: &ArgMatches, request: ControlRequest) -> Result<ControlResponse, AccessError> {
+    let path = match matches.value_of("control-socket") {
+        Some(path) => path.to_string(),
+        None => ServerConfig::read(matches.value_of("config-file").unwrap())?
+            .control_socket
+            .ok_or_else(|| AccessError::InvalidConfig(String::from("control_socket isn't set")))?,
+    };
+    let mut stream = UnixStream::connect(&path)
+        .map_err(|e| AccessError::FileError(format!("couldn't connect to {}: {}", path, e)))?;
+    let mut reply = Vec::new();
+    stream
+        .write_all(&request.to_msg()?)
+        .and_then(|_| stream.shutdown(Shutdown::Write))
+        .and_then(|_| stream.read_to_end(&mut reply))
+        .map_err(AccessError::IoError)?;
+    ControlResponse::from_msg(&reply)
+}
+
+fn print_sessions(sessions: &[SessionInfo]) {
+    if sessions.is_empty() {
+        println!("no sessions");
+        return;
+    }
+    println!(
+        "{:<39} {:<12} {:<16} {:>9} {:>8}",
+        "ADDRESS", "SERVICE", "KEY", "REMAINING", "RENEWALS"
+    );
+    for sess in sessions {
+        println!(
+            "{:<39} {:<12} {:<16} {:>9} {:>8}",
+            sess.addr, sess.service, sess.key, sess.remaining, sess.renewals
+        );
+    }
+}
+
+fn sessions(matches: &ArgMatches) -> Result<(), AccessError> {
+    let addr = |m: &ArgMatches| {
+        IpAddr::from_str(m.value_of("ADDR").unwrap()).map_err(AccessError::InvalidAddr)
+    };
+    let seconds = |value: &str| {
+        value
+            .parse::<u64>()
+            .map_err(|e| AccessError::InvalidConfig(format!("{}: {}", value, e)))
+    };
+    let (command, m) = matches.subcommand();
+    let m = m.unwrap();
+    let request = match command {
+        "list" => ControlRequest::List,
+        "revoke" => ControlRequest::Revoke {
+            addr: addr(m)?,
+            service: m.value_of("service").unwrap().into(),
+        },
+        "extend" => ControlRequest::Extend {
+            addr: addr(m)?,
+            service: m.value_of("service").unwrap().into(),
+            seconds: seconds(m.value_of("SECONDS").unwrap())?,
+        },
+        "grant" => ControlRequest::Grant {
+            addr: addr(m)?,
+            service: m.value_of("service").unwrap().into(),
+            duration: match m.value_of("duration") {
+                Some(duration) => Some(seconds(duration)?),
+                None => None,
+            },
+        },
+        _ => ControlRequest::Counters,
+    };
+    match control(m, request)? {
+        ControlResponse::Sessions(sessions) => print_sessions(&sessions),
+        ControlResponse::Counters(counters) => print!("{}", counters),
+        ControlResponse::Done(msg) => println!("{}", msg),
+        ControlResponse::Error(msg) => return Err(AccessError::ControlError(msg)),
+    }
+    Ok(())
+}
+
+/// Changes the key data file. The server picks up the change on SIGHUP.
+fn keys(matches: &ArgMatches) -> Result<(), AccessError> {
+    let (command, m) = matches.subcommand();
+    let m = m.unwrap();
+    let path = m.value_of("key-data-file").unwrap();
+    let mut key_data = ServerKeyData::read(path)?;
+
+    if command == "list" {
+        let mut names: Vec<&String> = key_data.peer_public_keys.keys().collect();
+        names.sort();
+        for name in names {
+            let peer_key = &key_data.peer_public_keys[name];
+            if peer_key.policy.enabled {
+                println!("{}", name);
+            } else {
+                println!("{} (disabled)", name);
+            }
+        }
+        return Ok(());
+    }
+
+    let name = m.value_of("NAME").unwrap();
+    let done = match command {
+        "add" => {
+            let public = keys::public_key_from_hex(m.value_of("PUBKEY").unwrap())?;
+            if key_data.peer_public_keys.contains_key(name) {
+                return Err(AccessError::InvalidKey(format!("{} already exists", name)));
+            }
+            key_data.peer_public_keys.insert(
+                name.into(),
+                PeerKey {
+                    public,
+                    policy: KeyPolicy::default(),
+                },
+            );
+            format!("added {}", name)
+        }
+        "remove" => {
+            if key_data.peer_public_keys.remove(name).is_none() {
+                return Err(AccessError::InvalidKey(format!("no key named {}", name)));
+            }
+            format!("removed {}", name)
+        }
+        _ => {
+            let enable = command == "enable";
+            match key_data.peer_public_keys.get_mut(name) {
+                Some(peer_key) => peer_key.policy.enabled = enable,
+                None => return Err(AccessError::InvalidKey(format!("no key named {}", name))),
+            }
+            format!("{}d {}", command, name)
+        }
+    };
+    key_data.write(path)?;
+    println!("{}; send SIGHUP to accessd to apply it", done);
+    Ok(())
+}
+
+fn show_state(matches: &ArgMatches) -> Result<(), AccessError> {
+    let state = ServerState::read(matches.value_of("state-file").unwrap())?;
+    let now = unix_now();
+
+    println!("request IDs:");
+    let mut req_ids: Vec<(&String, &u64)> = state.cur_req_ids.iter().collect();
+    req_ids.sort();
+    for (name, req_id) in req_ids {
+        println!("  {}: {}", name, req_id);
+    }
+    println!("sessions:");
+    for saved in &state.sessions {
+        let expiry = if saved.revoke_pending {
+            String::from("left open on shutdown")
+        } else if saved.expiry > now {
+            format!("expires in {} seconds", saved.expiry - now)
+        } else {
+            String::from("expired")
+        };
+        println!(
+            "  {} ({}) for {}, {}, {} renewals",
+            saved.addr, saved.service, saved.name, expiry, saved.renewals
+        );
+    }
+    Ok(())
+}
+
+fn main() {
+    // Only warnings and errors are logged; the results of commands are printed.
+    if let Err(e) = logger::init("accessctl", LogTarget::Stdout, LevelFilter::Warn) {
+        eprintln!("couldn't set up logging: {}", e);
+    }
+
+    let addr_arg = || {
+        Arg::with_name("ADDR")
+            .required(true)
+            .help("Address the session is for")
+    };
+    let service_arg = || {
+        Arg::with_name("service")
+            .empty_values(false)
+            .short("S")
+            .long("service")
+            .default_value(DEFAULT_SERVICE)
+            .help("Service the session is for")
+    };
+    let name_arg = || Arg::with_name("NAME").required(true).help("Key name");
+
+    let matches = App::new("accessctl")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Manages a running accessd and its files")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("config-file")
+                .global(true)
+                .empty_values(false)
+                .short("c")
+                .long("config-file")
+                .default_value(DEFAULT_CONFIG_FILENAME)
+                .help("Path to server configuration file, for the control socket"),
+        )
+        .arg(
+            Arg::with_name("control-socket")
+                .global(true)
+                .empty_values(false)
+                .long("socket")
+                .help("Path to control socket (default: control_socket in the configuration)"),
+        )
+        .arg(
+            Arg::with_name("key-data-file")
+                .global(true)
+                .empty_values(false)
+                .short("k")
+                .long("key-data-file")
+                .default_value(DEFAULT_KEYDATA_FILENAME)
+                .help("Path to key data file"),
+        )
+        .arg(
+            Arg::with_name("state-file")
+                .global(true)
+                .empty_values(false)
+                .short("s")
+                .long("state-file")
+                .default_value(DEFAULT_STATE_FILENAME)
+                .help("Path to state file"),
+        )
+        .subcommand(
+            SubCommand::with_name("sessions")
+                .about("Manages the server's sessions through its control socket")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("list").about("Lists open sessions"))
+                .subcommand(
+                    SubCommand::with_name("revoke")
+                        .about("Closes a session")
+                        .arg(service_arg())
+                        .arg(addr_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("extend")
+                        .about("Adds time to a session, without counting as a renewal")
+                        .arg(service_arg())
+                        .arg(addr_arg())
+                        .arg(
+                            Arg::with_name("SECONDS")
+                                .required(true)
+                                .help("Seconds to add"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("grant")
+                        .about("Opens a session without a client request")
+                        .arg(service_arg())
+                        .arg(
+                            Arg::with_name("duration")
+                                .empty_values(false)
+                                .short("d")
+                                .long("duration")
+                                .help("Session duration in seconds (default: the server's)"),
+                        )
+                        .arg(addr_arg()),
+                )
+                .subcommand(SubCommand::with_name("counters").about("Shows the server's counters")),
+        )
+        .subcommand(
+            SubCommand::with_name("keys")
+                .about("Edits the key data file")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("list").about("Lists the client keys"))
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Adds a client key")
+                        .arg(name_arg())
+                        .arg(
+                            Arg::with_name("PUBKEY")
+                                .required(true)
+                                .help("Public key, in hex"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("Removes a client key")
+                        .arg(name_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("disable")
+                        .about("Stops a client key from being accepted, keeping its settings")
+                        .arg(name_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("enable")
+                        .about("Accepts a disabled client key again")
+                        .arg(name_arg()),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("state")
+                .about("Inspects the server state file")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("show").about("Shows the request IDs and saved sessions"),
+                ),
+        )
+        .get_matches();
+
+    let result = match matches.subcommand() {
+        ("sessions", Some(m)) => sessions(m),
+        ("keys", Some(m)) => keys(m),
+        (_, Some(m)) => show_state(m.subcommand_matches("show").unwrap()),
+        _ => Ok(()),
+    };
+    if let Err(e) = result {
+        eprintln!("failed: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -1,37 +1,53 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::clone::Clone;
 use std::collections::hash_map::Entry::*;
-use std::collections::HashMap;
-use std::fs::File;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
 use std::io;
 use std::net::{IpAddr, SocketAddr};
-use std::process::Command;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net;
+use std::os::unix::process::CommandExt as _;
+use std::process::{Command, Output, Stdio};
 use std::rc::Rc;
-use std::str;
+use std::str::FromStr;
 use std::time::{Duration, Instant};
 
+use access::audit::{AuditEvent, AuditLog, AuditRecord, RevokeReason};
+use access::backend::{Backend, CommandStatus};
+use access::clock::unix_now;
+use access::config::{RemovedKeyAction, ServerConfig, DEFAULT_CONFIG_FILENAME};
+use access::control::{ControlRequest, ControlResponse, SessionInfo, CONTROL_KEY};
 use access::err::AccessError;
-use access::keys::{KeyDataReader, ServerKeyData};
-use access::packet;
-use access::req::{ReqData, SessReq, REQ_PORT};
-use access::resp::{SessReqAction, SessResp};
-use access::state::{ServerState, StateManager};
-use clap::{crate_authors, crate_version, App, Arg};
+use access::keys::{KeyDataReader, KeyPolicy, ServerKeyData, DEFAULT_KEYDATA_FILENAME};
+use access::logger::{self, LogTarget};
+use access::metrics::Metrics;
+use access::packet::{self, Channel, Format};
+use access::replay::WindowCheck;
+use access::req::{ReqData, SessReq};
+use access::resp::{SessReqAction, SessResp, SessStatus};
+use access::state::{SavedSession, ServerState, StateManager, DEFAULT_STATE_FILENAME};
+use clap::{crate_authors, crate_version, App, Arg, ArgMatches};
 use daemonize::Daemonize;
+use futures::future::{Either, Loop};
+use futures::sync::{mpsc, oneshot};
 use futures::{future, Future, Stream};
-use sodiumoxide::crypto::box_::{Nonce, NONCEBYTES};
-use sodiumoxide::randombytes::randombytes;
-use tokio_core::net::{UdpCodec, UdpSocket};
+use log::{debug, error, info, warn, LevelFilter};
+use tokio_core::net::{TcpListener, TcpStream, UdpCodec, UdpSocket};
 use tokio_core::reactor::{Core, Handle, Timeout};
 use tokio_process::CommandExt;
+use tokio_signal::unix::{Signal, SIGHUP, SIGINT, SIGTERM};
+use tokio_uds::{UnixListener, UnixStream};
 
-const MAX_RENEWALS: u8 = 4;
-const DEFAULT_DURATION: &str = "900";
-const DEFAULT_STATE_FILENAME: &str = "/var/db/accessd_state.yaml";
-const DEFAULT_KEYDATA_FILENAME: &str = "/etc/accessd_keydata.yaml";
+const REVOKE_ATTEMPTS: u32 = 5;
+const REVOKE_RETRY_DELAY: u64 = 1;
 
-const DEFAULT_DAEMON_STDOUT_FILENAME: &str = "/var/log/accessd.out";
-const DEFAULT_DAEMON_STDERR_FILENAME: &str = "/var/log/accessd.err";
+enum ReqType {
+    TimedAccess,
+    Revoke,
+    Status,
+}
 
 enum TimeoutCompleteAction {
     Revoke,
@@ -39,227 +55,1115 @@ enum TimeoutCompleteAction {
     Unknown,
 }
 
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct SessionKey {
+    addr: String,
+    service: String,
+}
+
+impl fmt::Display for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.addr, self.service)
+    }
+}
+
 pub struct SessionInterval {
+    name: String,
+    backend: Backend,
+    req_id: u64,
+    src_addr: IpAddr,
     session_start: Instant,
     timeout_start: Instant,
+    duration: u64,
     renew_ok: bool,
     renewals: u8,
+    revoke_pending: bool,
+    timeouts: Vec<oneshot::Sender<()>>,
 }
 
 impl SessionInterval {
-    fn new() -> SessionInterval {
+    fn new(sess: &Session) -> SessionInterval {
         SessionInterval {
+            name: sess.name.clone(),
+            backend: sess.backend.clone(),
+            req_id: sess.req_id,
+            src_addr: sess.src_addr,
             session_start: Instant::now(),
             timeout_start: Instant::now(),
+            duration: sess.duration,
             renew_ok: true,
             renewals: 0,
+            revoke_pending: false,
+            timeouts: Vec::new(),
+        }
+    }
+
+    /// Rebuilds an interval saved by a previous run. An `Instant` can't
+    /// reach back past when the system booted, so the timeout starts now and
+    /// runs for whatever was left of it, as when a session is extended.
+    fn from_saved(saved: &SavedSession, sess: &Session, now: u64) -> SessionInterval {
+        let mut sess_interval = SessionInterval::new(sess);
+        sess_interval.session_start = Instant::now()
+            .checked_sub(Duration::from_secs(now.saturating_sub(saved.start)))
+            .unwrap_or_else(Instant::now);
+        sess_interval.duration = saved.expiry.saturating_sub(now);
+        sess_interval.renewals = saved.renewals;
+        sess_interval
+    }
+
+    fn to_saved(&self, key: &SessionKey, now: u64) -> SavedSession {
+        SavedSession {
+            addr: key.addr.clone(),
+            service: key.service.clone(),
+            name: self.name.clone(),
+            backend: self.backend.clone(),
+            start: now.saturating_sub(self.session_start.elapsed().as_secs()),
+            expiry: now
+                + self
+                    .duration
+                    .saturating_sub(self.timeout_start.elapsed().as_secs()),
+            duration: self.duration,
+            renewals: self.renewals,
+            req_id: self.req_id,
+            source: Some(self.src_addr),
+            revoke_pending: self.revoke_pending,
+        }
+    }
+
+    fn audit_record(&self, key: &SessionKey, event: AuditEvent) -> AuditRecord {
+        let mut record = AuditRecord::new(event, &self.name, self.req_id);
+        record.source = Some(self.src_addr);
+        record.addr = Some(key.addr.clone());
+        record.service = Some(key.service.clone());
+        record.duration = Some(self.duration);
+        record
+    }
+
+    /// Describes the session to the firewall commands. It goes in the
+    /// environment, so the commands' arguments stay the same.
+    fn env(&self, key: &SessionKey) -> Vec<(&'static str, String)> {
+        let saved = self.to_saved(key, unix_now());
+        vec![
+            ("ACCESSD_KEY", saved.name),
+            ("ACCESSD_REQ_ID", saved.req_id.to_string()),
+            ("ACCESSD_ADDR", saved.addr),
+            ("ACCESSD_SERVICE", saved.service),
+            ("ACCESSD_SOURCE", self.src_addr.to_string()),
+            ("ACCESSD_DURATION", saved.duration.to_string()),
+            ("ACCESSD_RENEWALS", saved.renewals.to_string()),
+            ("ACCESSD_START", saved.start.to_string()),
+            ("ACCESSD_EXPIRY", saved.expiry.to_string()),
+        ]
+    }
+}
+
+/// How firewall commands are run. Sessions take these from the configuration
+/// when their request comes in, like the backend.
+#[derive(Clone, Default)]
+struct CommandSettings {
+    timeout: u64,
+    alarm_command: Option<String>,
+}
+
+impl CommandSettings {
+    fn new(config: &ServerConfig) -> Self {
+        CommandSettings {
+            timeout: config.command_timeout,
+            alarm_command: config.alarm_command.clone(),
         }
     }
 }
 
 pub struct Session {
-    cmd: String,
+    name: String,
+    backend: Backend,
+    commands: CommandSettings,
+    audit: AuditLog,
+    metrics: Metrics,
+    req_type: ReqType,
     req_id: u64,
+    timestamp: Option<u64>,
+    nonce: Vec<u8>,
     duration: u64,
     req_addr: String,
+    src_addr: IpAddr,
+    service: String,
     handle: Handle,
 }
 
 impl Session {
-    fn new(cmd: &str, req_id: u64, duration: u64, req_addr: IpAddr, handle: &Handle) -> Self {
+    fn new(
+        req_type: ReqType,
+        req_id: u64,
+        duration: u64,
+        req_addr: IpAddr,
+        src_addr: IpAddr,
+        service: &str,
+        handle: &Handle,
+    ) -> Self {
         Session {
-            cmd: cmd.into(),
+            name: String::new(),
+            backend: Backend::default(),
+            commands: CommandSettings::default(),
+            audit: AuditLog::default(),
+            metrics: Metrics::default(),
+            req_type,
             req_id,
+            timestamp: None,
+            nonce: Vec::new(),
             duration,
             req_addr: req_addr.to_string(),
+            src_addr,
+            service: service.into(),
             handle: handle.clone(),
         }
     }
+
+    fn key(&self) -> SessionKey {
+        SessionKey {
+            addr: self.req_addr.clone(),
+            service: self.service.clone(),
+        }
+    }
+
+    /// Starts an audit record about this request. Only requests for access
+    /// have a duration.
+    fn audit_record(&self, event: AuditEvent) -> AuditRecord {
+        let mut record = AuditRecord::new(event, &self.name, self.req_id);
+        record.source = Some(self.src_addr);
+        record.addr = Some(self.req_addr.clone());
+        record.service = Some(self.service.clone());
+        if let ReqType::TimedAccess = self.req_type {
+            record.duration = Some(self.duration);
+        }
+        record
+    }
 }
 
+/// Each listening socket gets its own codec. The clones share the sessions,
+/// the replay state, the keys and the configuration.
+#[derive(Clone)]
 pub struct ServerCodec {
-    cmd: String,
-    duration: u64,
     handle: Handle,
-    sessions: Rc<RefCell<HashMap<String, SessionInterval>>>,
-    state: ServerState,
-    key_data: ServerKeyData,
+    sessions: Rc<RefCell<HashMap<SessionKey, SessionInterval>>>,
+    state: Rc<RefCell<ServerState>>,
+    key_data: Rc<RefCell<ServerKeyData>>,
+    channels: Rc<RefCell<HashMap<String, Channel>>>,
+    config: Rc<RefCell<ServerConfig>>,
+    audit: AuditLog,
+    metrics: Metrics,
+    /// Grants and renewals whose commands haven't finished.
+    in_progress: Rc<Cell<usize>>,
 }
 
 impl ServerCodec {
+    /// Without a state file the request IDs would start over and old
+    /// requests could be replayed, so the server never starts one itself.
     fn new(
         state_filename: &str,
         key_data_filename: &str,
-        access_cmd: &str,
-        duration: u64,
+        config: ServerConfig,
         handle: &Handle,
     ) -> Result<Self, AccessError> {
-        let state = ServerState::read(state_filename)?;
+        let mut state = ServerState::read(state_filename).map_err(|e| {
+            AccessError::FileError(format!(
+                "{}; use --init-state to create a new state file before the first start",
+                e
+            ))
+        })?;
+        state.replay.set_started(unix_now());
         let key_data = ServerKeyData::read(key_data_filename)?;
+        let audit = AuditLog::open(config.audit_log.as_deref())?;
 
         Ok(ServerCodec {
-            cmd: String::from(access_cmd),
-            duration,
             handle: handle.clone(),
             sessions: Rc::new(RefCell::new(HashMap::new())),
-            state,
-            key_data,
+            state: Rc::new(RefCell::new(state)),
+            channels: Rc::new(RefCell::new(open_channels(&key_data))),
+            key_data: Rc::new(RefCell::new(key_data)),
+            config: Rc::new(RefCell::new(config)),
+            audit,
+            metrics: Metrics::default(),
+            in_progress: Rc::new(Cell::new(0)),
         })
     }
 
-    fn get_sess(&mut self, addr: &SocketAddr, buf: &[u8]) -> Option<(String, Session)> {
-        for (name, public_key) in &self.key_data.peer_public_keys {
-            if let Ok(req_packet) = packet::open(buf, &self.key_data.secret, public_key) {
-                match SessReq::from_msg(&req_packet) {
-                    Ok(recv_req) => {
-                        match recv_req.req_data {
-                            ReqData::TimedAccess(ip_addr) => {
-                                return Some((
-                                    name.clone(),
-                                    Session::new(
-                                        &self.cmd,
-                                        recv_req.req_id,
-                                        self.duration,
-                                        if ip_addr.is_unspecified() {
-                                            addr.ip()
-                                        } else {
-                                            ip_addr
-                                        },
-                                        &self.handle,
-                                    ),
-                                ));
+    /// Picks up the sessions saved by a previous run: ones that are still
+    /// valid get their timers back, and ones that expired while the server
+    /// was down, or that the last shutdown couldn't revoke, are revoked
+    /// straight away.
+    fn restore_sessions(&self) {
+        let saved_sessions = self.state.borrow().sessions.clone();
+        let now = unix_now();
+
+        for saved in saved_sessions {
+            let sess = match self.existing_session(&saved) {
+                Some(sess) => sess,
+                None => continue,
+            };
+            self.sessions
+                .borrow_mut()
+                .insert(sess.key(), SessionInterval::from_saved(&saved, &sess, now));
+
+            if saved.revoke_pending {
+                info!("session for {} was left open on shutdown", sess.key());
+                revoke_access(
+                    sess,
+                    self.sessions.clone(),
+                    self.state.clone(),
+                    RevokeReason::Shutdown,
+                );
+            } else if saved.expiry <= now {
+                info!("session for {} expired while stopped", sess.key());
+                revoke_access(
+                    sess,
+                    self.sessions.clone(),
+                    self.state.clone(),
+                    RevokeReason::Expired,
+                );
+            } else {
+                info!(
+                    "restoring session for {}, {} seconds left",
+                    sess.key(),
+                    saved.expiry - now
+                );
+                start_timeout(
+                    sess,
+                    self.sessions.clone(),
+                    self.state.clone(),
+                    saved.expiry - now,
+                );
+            }
+        }
+        save_sessions(&self.sessions, &self.state, &self.metrics);
+    }
+
+    /// Makes a `Session` for a session that is already open, so that it can
+    /// be timed or revoked like one that was just requested.
+    fn existing_session(&self, saved: &SavedSession) -> Option<Session> {
+        let addr: IpAddr = match saved.addr.parse() {
+            Ok(addr) => addr,
+            Err(e) => {
+                warn!("ignoring saved session for {}: {}", saved.addr, e);
+                return None;
+            }
+        };
+        let mut sess = Session::new(
+            ReqType::TimedAccess,
+            saved.req_id,
+            saved.duration,
+            addr,
+            saved.source.unwrap_or(addr),
+            &saved.service,
+            &self.handle,
+        );
+        sess.name = saved.name.clone();
+        sess.backend = saved.backend.clone();
+        sess.commands = CommandSettings::new(&self.config.borrow());
+        sess.audit = self.audit.clone();
+        sess.metrics = self.metrics.clone();
+        Some(sess)
+    }
+
+    /// Rereads the key data and the configuration. A file that can't be
+    /// loaded leaves the data from it as it was.
+    fn reload(
+        &self,
+        key_data_filename: &str,
+        load_config: &dyn Fn() -> Result<ServerConfig, AccessError>,
+    ) {
+        match ServerKeyData::read(key_data_filename) {
+            Ok(key_data) => {
+                *self.channels.borrow_mut() = open_channels(&key_data);
+                *self.key_data.borrow_mut() = key_data;
+                info!("reloaded {}", key_data_filename);
+            }
+            Err(e) => warn!("keeping previous key data: {}", e),
+        }
+
+        match load_config() {
+            Ok(config) => {
+                {
+                    let old_config = self.config.borrow();
+                    if config.listen_addrs != old_config.listen_addrs
+                        || config.port != old_config.port
+                    {
+                        warn!("listen address changes take effect after a restart");
+                    }
+                    if config.log_target != old_config.log_target {
+                        warn!("log target changes take effect after a restart");
+                    }
+                }
+                log::set_max_level(config.log_level);
+                *self.config.borrow_mut() = config;
+                info!("reloaded configuration");
+                self.reload_firewalls();
+            }
+            Err(e) => warn!("keeping previous configuration: {}", e),
+        }
+
+        // Reopening lets the audit log be rotated.
+        let audit_log = self.config.borrow().audit_log.clone();
+        if let Err(e) = self.audit.reopen(audit_log.as_deref()) {
+            warn!("keeping previous audit log: {}", e);
+        }
+
+        self.close_removed_key_sessions();
+    }
+
+    /// Runs the setup step of every service's backend again, for services
+    /// added or changed by a reload. The commands run like any other, so a
+    /// slow one can't hold up requests. A failure is only logged, since the
+    /// server is already running.
+    fn reload_firewalls(&self) {
+        let config = self.config.borrow();
+        let commands = CommandSettings::new(&config);
+        for (service, service_config) in &config.services {
+            for command in service_config.backend.firewall().setup() {
+                let service = service.clone();
+                self.handle.spawn(
+                    run_command("setup", command, &commands, &self.metrics, &self.handle).map(
+                        move |status| {
+                            if !status.success() {
+                                error!("setting up the firewall for {} failed", service);
                             }
-                        };
+                        },
+                    ),
+                );
+            }
+        }
+    }
+
+    fn close_removed_key_sessions(&self) {
+        let now = unix_now();
+        let removed: Vec<SavedSession> = {
+            let key_data = self.key_data.borrow();
+            self.sessions
+                .borrow()
+                .iter()
+                .filter(|(_, sess_interval)| {
+                    sess_interval.name != CONTROL_KEY
+                        && !key_data.peer_public_keys.contains_key(&sess_interval.name)
+                })
+                .map(|(key, sess_interval)| sess_interval.to_saved(key, now))
+                .collect()
+        };
+        let removed_keys = self.config.borrow().removed_keys;
+
+        for saved in removed {
+            match removed_keys {
+                RemovedKeyAction::Revoke => {
+                    info!("key {} removed, revoking {}", saved.name, saved.addr);
+                    if let Some(sess) = self.existing_session(&saved) {
+                        revoke_access(
+                            sess,
+                            self.sessions.clone(),
+                            self.state.clone(),
+                            RevokeReason::KeyRemoved,
+                        );
                     }
-                    Err(e) => println!("invalid message from {:?}: {}", addr, e),
+                }
+                RemovedKeyAction::Expire => info!(
+                    "key {} removed, leaving {} open until it expires",
+                    saved.name, saved.addr
+                ),
+            }
+        }
+    }
+
+    /// Finds the key a packet was sent with and decrypts it. Packets with a
+    /// header name their key with a hint; older ones have to be tried
+    /// against every key. A packet from a known key can still fail to open
+    /// if it uses a protocol version this server doesn't speak.
+    fn open_packet(&self, buf: &[u8]) -> Option<(String, Result<Vec<u8>, AccessError>, Format)> {
+        let channels = self.channels.borrow();
+        if let Some((name, channel)) = channels.iter().find(|(_, channel)| channel.is_hinted(buf)) {
+            match channel.open(buf, Format::Header) {
+                Err(AccessError::InvalidCiphertext) | Err(AccessError::InvalidNonce) => {}
+                opened => return Some((name.clone(), opened, Format::Header)),
+            }
+        }
+        if self.config.borrow().accept_legacy_packets {
+            for (name, channel) in channels.iter() {
+                if let Ok(req_packet) = channel.open(buf, Format::Legacy) {
+                    return Some((name.clone(), Ok(req_packet), Format::Legacy));
                 }
             }
         }
         None
     }
+
+    fn get_request(&mut self, addr: &SocketAddr, buf: &[u8]) -> Request {
+        let (name, req_packet, format) = match self.open_packet(buf) {
+            Some((name, Ok(req_packet), format)) => (name, req_packet, format),
+            Some((name, Err(AccessError::UnsupportedVersion(version)), _)) => {
+                self.metrics.request(&name);
+                return Request::UnsupportedVersion(name, version);
+            }
+            _ => {
+                self.metrics.decrypt_failed();
+                return Request::Invalid;
+            }
+        };
+        self.metrics.request(&name);
+        let key_data = self.key_data.borrow();
+        let config = self.config.borrow();
+        let peer_key = match key_data.peer_public_keys.get(&name) {
+            Some(peer_key) => peer_key,
+            None => return Request::Invalid,
+        };
+        match SessReq::from_msg(&req_packet) {
+            Ok(recv_req) => {
+                let (req_type, ip_addr) = match recv_req.req_data {
+                    ReqData::TimedAccess(ip_addr) => (ReqType::TimedAccess, ip_addr),
+                    ReqData::Revoke(ip_addr) => (ReqType::Revoke, ip_addr),
+                    ReqData::Status(ip_addr) => (ReqType::Status, ip_addr),
+                };
+                let max_duration = peer_key
+                    .policy
+                    .max_duration
+                    .unwrap_or_else(|| config.max_duration());
+                let mut sess = Session::new(
+                    req_type,
+                    recv_req.req_id,
+                    recv_req
+                        .duration
+                        .filter(|duration| *duration > 0)
+                        .unwrap_or(config.duration)
+                        .min(max_duration),
+                    if ip_addr.is_unspecified() {
+                        addr.ip().to_canonical()
+                    } else {
+                        ip_addr
+                    },
+                    addr.ip().to_canonical(),
+                    // Clients from before services don't name one.
+                    if recv_req.service.is_empty() {
+                        &config.default_service
+                    } else {
+                        &recv_req.service
+                    },
+                    &self.handle,
+                );
+                sess.timestamp = recv_req.timestamp;
+                sess.nonce = packet::nonce(buf, format).to_vec();
+                sess.audit = self.audit.clone();
+                sess.metrics = self.metrics.clone();
+                Request::Session(name, Box::new(sess), format)
+            }
+            Err(e) => {
+                debug!("invalid message from {:?}: {}", addr, e);
+                Request::Invalid
+            }
+        }
+    }
+}
+
+fn open_channels(key_data: &ServerKeyData) -> HashMap<String, Channel> {
+    key_data
+        .peer_public_keys
+        .iter()
+        .map(|(name, peer_key)| {
+            (
+                name.clone(),
+                Channel::new(&key_data.secret, &peer_key.public),
+            )
+        })
+        .collect()
+}
+
+/// What a datagram turned out to be. Requests carry the name of the key they
+/// were sent with, and the packet format to answer in.
+pub enum Request {
+    Session(String, Box<Session>, Format),
+    UnsupportedVersion(String, u8),
+    Invalid,
 }
 
-/* TODO: the data passed around by the codec is baroque. In particular,
-   what's up with the tuple in the "session" Option?
-*/
 pub struct CodecReqState {
     sock_addr: SocketAddr,
-    session: Option<(String, Session)>,
-    sessions: Rc<RefCell<HashMap<String, SessionInterval>>>,
+    request: Request,
+    sessions: Rc<RefCell<HashMap<SessionKey, SessionInterval>>>,
 }
 
 impl CodecReqState {
     fn new(
         sock_addr: SocketAddr,
-        session: Option<(String, Session)>,
-        sessions: &Rc<RefCell<HashMap<String, SessionInterval>>>,
+        request: Request,
+        sessions: &Rc<RefCell<HashMap<SessionKey, SessionInterval>>>,
     ) -> Self {
         Self {
             sock_addr,
-            session,
+            request,
             sessions: sessions.clone(),
         }
     }
 }
 
-impl UdpCodec for ServerCodec {
-    type In = CodecReqState;
-    type Out = CodecReqState;
+/// A response to send to a client, sealed with its channel when it is sent.
+pub struct Response {
+    sock_addr: SocketAddr,
+    name: String,
+    resp: SessResp,
+    format: Format,
+}
 
-    fn decode(&mut self, addr: &SocketAddr, buf: &[u8]) -> io::Result<Self::In> {
-        Ok(CodecReqState::new(
-            *addr,
-            self.get_sess(addr, buf),
-            &self.sessions,
-        ))
+/// Sends responses that aren't known until a command finishes to the socket
+/// the request came in on.
+type Responder = mpsc::UnboundedSender<Response>;
+
+impl ServerCodec {
+    /// Counts a grant or renewal as in progress until its command finishes,
+    /// so that a shutdown can wait for it.
+    fn in_progress<F>(&self, command: F) -> impl Future<Item = F::Item, Error = ()>
+    where
+        F: Future<Error = ()>,
+    {
+        let in_progress = self.in_progress.clone();
+        in_progress.set(in_progress.get() + 1);
+        command.then(move |result| {
+            in_progress.set(in_progress.get() - 1);
+            result
+        })
+    }
+
+    fn grant(
+        &self,
+        sess: Session,
+        sessions: Rc<RefCell<HashMap<SessionKey, SessionInterval>>>,
+    ) -> impl Future<Item = CommandStatus, Error = ()> {
+        self.in_progress(grant_access(sess, sessions, self.state.clone()))
+    }
+
+    fn renew(
+        &self,
+        sess: Session,
+        sessions: Rc<RefCell<HashMap<SessionKey, SessionInterval>>>,
+    ) -> impl Future<Item = bool, Error = ()> {
+        self.in_progress(renew_access(sess, sessions, self.state.clone()))
     }
 
-    fn encode(&mut self, req_state: Self::Out, into: &mut Vec<u8>) -> SocketAddr {
-        match req_state.session {
-            Some((name, req_sess)) => {
-                let resp = match handle_incoming(
+    /// Acts on a request. Grants and renewals are answered through
+    /// `responder` once their commands finish, so they return nothing here.
+    fn handle_request(&self, req_state: CodecReqState, responder: &Responder) -> Option<Response> {
+        let (name, resp, format) = match req_state.request {
+            Request::Session(name, req_sess, format) => {
+                let mut req_sess = *req_sess;
+                let key_data = self.key_data.borrow();
+                let peer_key = match key_data.peer_public_keys.get(&name) {
+                    Some(peer_key) => peer_key,
+                    None => {
+                        warn!("no public key found for {}", name);
+                        return None;
+                    }
+                };
+                // Denials can come before the request is matched to a
+                // service, so name the session now for the audit log.
+                req_sess.name = name.clone();
+                let resp = handle_incoming(
                     &req_state.sessions,
                     name.clone(),
-                    &req_sess,
-                    &mut self.state,
-                ) {
+                    &mut req_sess,
+                    &mut self.state.borrow_mut(),
+                    &self.config.borrow(),
+                    &peer_key.policy,
+                );
+                // Grants and renewals are recorded once their commands finish.
+                match resp.action {
+                    SessReqAction::Grant | SessReqAction::Renew => {}
+                    action => {
+                        let mut record = req_sess.audit_record(AuditEvent::Request);
+                        record.action = Some(action);
+                        self.audit.record(record);
+                    }
+                }
+                let resp = match resp {
                     grant @ SessResp {
                         action: SessReqAction::Grant,
                         ..
                     } => {
-                        grant_access(req_sess, req_state.sessions);
-                        grant
+                        let mut response = Response {
+                            sock_addr: req_state.sock_addr,
+                            name,
+                            resp: grant,
+                            format,
+                        };
+                        let responder = responder.clone();
+                        self.handle
+                            .spawn(self.grant(req_sess, req_state.sessions).map(move |status| {
+                                if !status.success() {
+                                    response.resp = SessResp::new(
+                                        SessReqAction::GrantFailed,
+                                        response.resp.req_id,
+                                        0,
+                                        0,
+                                    );
+                                }
+                                let sock_addr = response.sock_addr;
+                                if responder.send(response).is_err() {
+                                    warn!("response to {} not sent", sock_addr);
+                                }
+                            }));
+                        return None;
                     }
                     renew @ SessResp {
                         action: SessReqAction::Renew,
                         ..
                     } => {
-                        renew_access(req_sess, req_state.sessions);
-                        renew
+                        let mut response = Response {
+                            sock_addr: req_state.sock_addr,
+                            name,
+                            resp: renew,
+                            format,
+                        };
+                        let responder = responder.clone();
+                        self.handle
+                            .spawn(
+                                self.renew(req_sess, req_state.sessions)
+                                    .map(move |renewed| {
+                                        if !renewed {
+                                            response.resp = SessResp::new(
+                                                SessReqAction::RenewFailed,
+                                                response.resp.req_id,
+                                                0,
+                                                0,
+                                            );
+                                        }
+                                        let sock_addr = response.sock_addr;
+                                        if responder.send(response).is_err() {
+                                            warn!("response to {} not sent", sock_addr);
+                                        }
+                                    }),
+                            );
+                        return None;
+                    }
+                    revoke @ SessResp {
+                        action: SessReqAction::Revoke,
+                        ..
+                    } => {
+                        revoke_access(
+                            req_sess,
+                            req_state.sessions,
+                            self.state.clone(),
+                            RevokeReason::Requested,
+                        );
+                        revoke
                     }
                     deny => deny,
                 };
+                // Answer in the format the request came in, so older clients
+                // can read the response.
+                (name, resp, format)
+            }
+            Request::UnsupportedVersion(name, version) => {
+                info!("{} sent protocol version {}", name, version);
+                let mut record = AuditRecord::new(AuditEvent::Request, &name, 0);
+                record.source = Some(req_state.sock_addr.ip().to_canonical());
+                record.action = Some(SessReqAction::DenyUnsupportedVersion);
+                self.audit.record(record);
+                (
+                    name,
+                    SessResp::new(SessReqAction::DenyUnsupportedVersion, 0, 0, 0),
+                    Format::Header,
+                )
+            }
+            Request::Invalid => {
+                debug!("invalid request from {:?}", req_state.sock_addr);
+                return None;
+            }
+        };
 
-                match self.key_data.peer_public_keys.get(&name) {
-                    Some(peer_public) => {
-                        let nonce: Nonce = Nonce::from_slice(&randombytes(NONCEBYTES)).unwrap();
-                        into.extend(&nonce[..]);
-                        match resp.to_msg() {
-                            Ok(msg) => {
-                                let encrypted_req_packet = packet::create(
-                                    &msg,
-                                    &nonce,
-                                    &self.key_data.secret,
-                                    peer_public,
-                                );
-                                into.extend(encrypted_req_packet);
-                            }
-                            Err(e) => println!("packet encoding failed: {}", e),
-                        }
-                    }
-                    None => println!("no public key found for {}", name),
+        Some(Response {
+            sock_addr: req_state.sock_addr,
+            name,
+            resp,
+            format,
+        })
+    }
+}
+
+impl ServerCodec {
+    /// Carries out a command from the control socket. Sessions are opened,
+    /// extended and closed the same way as for clients, so they are timed,
+    /// saved and audited as usual.
+    fn control(
+        &self,
+        request: ControlRequest,
+    ) -> Box<dyn Future<Item = ControlResponse, Error = ()>> {
+        let response = match request {
+            ControlRequest::List => ControlResponse::Sessions(self.session_list()),
+            ControlRequest::Revoke { addr, service } => self.control_revoke(addr, &service),
+            ControlRequest::Extend {
+                addr,
+                service,
+                seconds,
+            } => return self.control_extend(addr, &service, seconds),
+            ControlRequest::Grant {
+                addr,
+                service,
+                duration,
+            } => return self.control_grant(addr, &service, duration),
+            ControlRequest::Counters => {
+                ControlResponse::Counters(self.metrics.render(self.sessions.borrow().len()))
+            }
+        };
+        Box::new(future::ok(response))
+    }
+
+    fn session_list(&self) -> Vec<SessionInfo> {
+        let now = unix_now();
+        let mut list: Vec<SessionInfo> = self
+            .sessions
+            .borrow()
+            .iter()
+            .map(|(key, sess_interval)| {
+                let saved = sess_interval.to_saved(key, now);
+                SessionInfo {
+                    key: saved.name,
+                    addr: saved.addr,
+                    service: saved.service,
+                    source: saved.source,
+                    start: saved.start,
+                    expiry: saved.expiry,
+                    remaining: saved.expiry - now,
+                    renewals: saved.renewals,
                 }
+            })
+            .collect();
+        list.sort_by(|a, b| (&a.addr, &a.service).cmp(&(&b.addr, &b.service)));
+        list
+    }
+
+    fn control_revoke(&self, addr: IpAddr, service: &str) -> ControlResponse {
+        let key = SessionKey {
+            addr: addr.to_string(),
+            service: service.into(),
+        };
+        let saved = self
+            .sessions
+            .borrow()
+            .get(&key)
+            .map(|sess_interval| sess_interval.to_saved(&key, unix_now()));
+        match saved.and_then(|saved| self.existing_session(&saved)) {
+            Some(sess) => {
+                info!("revoking {} for the control socket", key);
+                revoke_access(
+                    sess,
+                    self.sessions.clone(),
+                    self.state.clone(),
+                    RevokeReason::Control,
+                );
+                ControlResponse::Done(format!("revoking {}", key))
             }
-            None => println!("invalid request from {:?}", req_state.sock_addr),
+            None => ControlResponse::Error(format!("no session for {}", key)),
         }
-        req_state.sock_addr
+    }
+
+    /// Adds `seconds` to the time a session has left. This works like a
+    /// renewal, so the firewall hears about the new expiry, but it doesn't
+    /// count against the key's renewals. The answer waits for the renew
+    /// command.
+    fn control_extend(
+        &self,
+        addr: IpAddr,
+        service: &str,
+        seconds: u64,
+    ) -> Box<dyn Future<Item = ControlResponse, Error = ()>> {
+        let key = SessionKey {
+            addr: addr.to_string(),
+            service: service.into(),
+        };
+        let max_duration = self.config.borrow().max_duration();
+        let saved = match self.sessions.borrow_mut().get_mut(&key) {
+            Some(sess_interval) => {
+                let remaining = sess_interval
+                    .duration
+                    .saturating_sub(sess_interval.timeout_start.elapsed().as_secs());
+                let duration = match remaining.checked_add(seconds) {
+                    Some(duration) if duration <= max_duration => duration,
+                    _ => {
+                        return Box::new(future::ok(ControlResponse::Error(format!(
+                            "{} can't have more than {} seconds left",
+                            key, max_duration
+                        ))))
+                    }
+                };
+                // Dropping the senders cancels the pending timeouts.
+                sess_interval.timeouts.clear();
+                sess_interval.timeout_start = Instant::now();
+                sess_interval.duration = duration;
+                sess_interval.renew_ok = true;
+                sess_interval.to_saved(&key, unix_now())
+            }
+            None => {
+                return Box::new(future::ok(ControlResponse::Error(format!(
+                    "no session for {}",
+                    key
+                ))))
+            }
+        };
+        match self.existing_session(&saved) {
+            Some(sess) => {
+                info!(
+                    "extending {} by {} seconds for the control socket",
+                    key, seconds
+                );
+                Box::new(self.renew(sess, self.sessions.clone()).map(move |renewed| {
+                    if renewed {
+                        ControlResponse::Done(format!(
+                            "{} has {} seconds left",
+                            key, saved.duration
+                        ))
+                    } else {
+                        ControlResponse::Error(format!(
+                            "renew command for {} failed, session revoked",
+                            key
+                        ))
+                    }
+                }))
+            }
+            None => Box::new(future::ok(ControlResponse::Error(format!(
+                "couldn't extend {}",
+                key
+            )))),
+        }
+    }
+
+    /// Opens a session for `addr` under the control key. The answer waits
+    /// for the grant command.
+    fn control_grant(
+        &self,
+        addr: IpAddr,
+        service: &str,
+        duration: Option<u64>,
+    ) -> Box<dyn Future<Item = ControlResponse, Error = ()>> {
+        let config = self.config.borrow();
+        let duration = duration.unwrap_or(config.duration);
+        if duration > config.max_duration() {
+            return Box::new(future::ok(ControlResponse::Error(format!(
+                "sessions can't be longer than {} seconds",
+                config.max_duration()
+            ))));
+        }
+        let mut sess = Session::new(
+            ReqType::TimedAccess,
+            0,
+            duration,
+            addr,
+            addr,
+            service,
+            &self.handle,
+        );
+        let key = sess.key();
+        if self.sessions.borrow().contains_key(&key) {
+            return Box::new(future::ok(ControlResponse::Error(format!(
+                "{} already has a session",
+                key
+            ))));
+        }
+        sess.backend = match config.service_backend(CONTROL_KEY, service) {
+            Ok(backend) => backend,
+            Err(deny) => {
+                return Box::new(future::ok(ControlResponse::Error(format!(
+                    "{}: {}",
+                    key, deny
+                ))))
+            }
+        };
+        sess.name = CONTROL_KEY.into();
+        sess.commands = CommandSettings::new(&config);
+        sess.audit = self.audit.clone();
+        sess.metrics = self.metrics.clone();
+
+        info!("granting {} for the control socket", key);
+        Box::new(self.grant(sess, self.sessions.clone()).map(move |status| {
+            if status.success() {
+                ControlResponse::Done(format!("granted {}", key))
+            } else {
+                ControlResponse::Error(format!("grant command for {} failed", key))
+            }
+        }))
+    }
+}
+
+impl UdpCodec for ServerCodec {
+    type In = CodecReqState;
+    type Out = Response;
+
+    fn decode(&mut self, addr: &SocketAddr, buf: &[u8]) -> io::Result<Self::In> {
+        self.metrics.packet_received();
+        Ok(CodecReqState::new(
+            *addr,
+            self.get_request(addr, buf),
+            &self.sessions,
+        ))
+    }
+
+    fn encode(&mut self, response: Self::Out, into: &mut Vec<u8>) -> SocketAddr {
+        self.metrics.response(response.resp.action);
+        match (
+            response.resp.to_msg(),
+            self.channels.borrow().get(&response.name),
+        ) {
+            (Ok(msg), Some(channel)) => into.extend(channel.seal(&msg, response.format)),
+            (Ok(_), None) => warn!("no channel found for {}", response.name),
+            (Err(e), _) => error!("packet encoding failed: {}", e),
+        }
+        response.sock_addr
     }
 }
 
 fn handle_incoming(
-    sessions: &Rc<RefCell<HashMap<String, SessionInterval>>>,
+    sessions: &Rc<RefCell<HashMap<SessionKey, SessionInterval>>>,
     name: String,
-    req_sess: &Session,
+    req_sess: &mut Session,
     state: &mut ServerState,
+    config: &ServerConfig,
+    policy: &KeyPolicy,
 ) -> SessResp {
+    // Requests without a timestamp come from older clients, and can't be
+    // shown to be fresh.
+    if let Some(max_age) = config.max_request_age {
+        let fresh = req_sess
+            .timestamp
+            .map(|timestamp| unix_now().abs_diff(timestamp) <= max_age)
+            .unwrap_or(false);
+        if !fresh {
+            return SessResp::new(SessReqAction::DenyStaleRequest, req_sess.req_id, 0, 0);
+        }
+    }
+    if !policy.enabled {
+        return SessResp::new(SessReqAction::DenyKeyDisabled, req_sess.req_id, 0, 0);
+    }
+    if !policy.permits_source(&req_sess.src_addr) {
+        return SessResp::new(SessReqAction::DenySourceNotPermitted, req_sess.req_id, 0, 0);
+    }
+
     let cur_req_id = match state.cur_req_ids.get(&name) {
         Some(req_id) => {
-            println!("session for {}, req_id {}", name, req_id);
+            debug!("session for {}, req_id {}", name, req_id);
             *req_id
         }
         None => {
-            println!("no request ID for {}", name);
+            debug!("no request ID for {}", name);
             0
         }
     };
 
-    if cur_req_id >= req_sess.req_id {
+    // Within the replay window, requests are told apart by their nonces, so
+    // their IDs may come in any order. The highest ID is still kept for the
+    // requests outside it.
+    let window_check = match config.replay_window {
+        Some(window) => state
+            .replay
+            .check(&req_sess.nonce, req_sess.timestamp, window, unix_now()),
+        None => WindowCheck::Outside,
+    };
+    let is_new = match window_check {
+        WindowCheck::Fresh => true,
+        WindowCheck::Replayed => false,
+        WindowCheck::Outside => cur_req_id < req_sess.req_id,
+    };
+    if !is_new {
         return SessResp::new(SessReqAction::DenyDuplicateRequest, req_sess.req_id, 0, 0);
-    } else {
-        state.cur_req_ids.insert(name, req_sess.req_id);
-        if let Err(e) = state.write() {
-            println!("state file write failed: {}", e)
+    }
+    if cur_req_id < req_sess.req_id {
+        state.cur_req_ids.insert(name.clone(), req_sess.req_id);
+    }
+    if let Err(e) = state.write() {
+        error!("state file write failed: {}", e);
+        req_sess.metrics.state_write_failed();
+    }
+
+    match config.service_backend(&name, &req_sess.service) {
+        Ok(_) if !policy.permits_service(&req_sess.service) => {
+            return SessResp::new(
+                SessReqAction::DenyServiceNotPermitted,
+                req_sess.req_id,
+                0,
+                0,
+            );
+        }
+        Ok(backend) => {
+            req_sess.name = name;
+            req_sess.backend = backend;
+            req_sess.commands = CommandSettings::new(config);
+        }
+        Err(deny) => return SessResp::new(deny, req_sess.req_id, 0, 0),
+    }
+
+    // req_addr always comes from an IpAddr, so it parses back.
+    let req_addr: IpAddr = req_sess.req_addr.parse().unwrap();
+    if !policy.permits_target(&req_addr)
+        || (config.require_source_match && !policy.delegate && req_addr != req_sess.src_addr)
+    {
+        return SessResp::new(
+            SessReqAction::DenyAddressNotPermitted,
+            req_sess.req_id,
+            0,
+            0,
+        );
+    }
+
+    let max_renewals = policy.max_renewals.unwrap_or(config.max_renewals);
+    match req_sess.req_type {
+        ReqType::TimedAccess => handle_timed_access(sessions, req_sess, config, max_renewals),
+        // A key may only close its own sessions. Another key's session is
+        // answered as if there were none, so it can't be found this way.
+        ReqType::Revoke => match sessions.borrow().get(&req_sess.key()) {
+            Some(sess_interval) if sess_interval.name == req_sess.name => {
+                SessResp::new(SessReqAction::Revoke, req_sess.req_id, 0, 0)
+            }
+            _ => SessResp::new(SessReqAction::DenyNoSession, req_sess.req_id, 0, 0),
+        },
+        ReqType::Status => {
+            let status = session_status(sessions, req_sess, config, max_renewals);
+            SessResp::new_status(req_sess.req_id, status)
         }
     }
+}
 
-    let client_addr = req_sess.req_addr.clone();
+fn session_status(
+    sessions: &Rc<RefCell<HashMap<SessionKey, SessionInterval>>>,
+    req_sess: &Session,
+    config: &ServerConfig,
+    max_renewals: u8,
+) -> SessStatus {
+    match sessions.borrow().get(&req_sess.key()) {
+        Some(sess_interval) if sess_interval.name == req_sess.name => {
+            let elapsed = sess_interval.timeout_start.elapsed().as_secs();
+            let renew_ok_after = config.renew_ok_after(sess_interval.duration);
+            SessStatus {
+                active: true,
+                remaining: sess_interval.duration.saturating_sub(elapsed),
+                renewals: sess_interval.renewals,
+                renewals_remaining: max_renewals.saturating_sub(sess_interval.renewals),
+                renew_ok_in: renew_ok_after.saturating_sub(elapsed),
+            }
+        }
+        _ => SessStatus {
+            active: false,
+            remaining: 0,
+            renewals: 0,
+            renewals_remaining: 0,
+            renew_ok_in: 0,
+        },
+    }
+}
+
+fn handle_timed_access(
+    sessions: &Rc<RefCell<HashMap<SessionKey, SessionInterval>>>,
+    req_sess: &Session,
+    config: &ServerConfig,
+    max_renewals: u8,
+) -> SessResp {
     let mut sessions_mut = sessions.borrow_mut();
-    let sess_interval = sessions_mut.entry(client_addr);
+    let sess_interval = sessions_mut.entry(req_sess.key());
     match sess_interval {
         Occupied(mut entry) => {
             let sess_interval_mut = entry.get_mut();
             let elapsed = sess_interval_mut.timeout_start.elapsed().as_secs();
-            let renew_ok_after = ((req_sess.duration as f64) * 0.75) as u64;
+            let renew_ok_after = config.renew_ok_after(sess_interval_mut.duration);
             if elapsed < renew_ok_after {
                 SessResp::new(
                     SessReqAction::DenyRenewTooSoon,
@@ -267,7 +1171,7 @@ fn handle_incoming(
                     renew_ok_after - elapsed,
                     0,
                 )
-            } else if sess_interval_mut.renewals >= MAX_RENEWALS {
+            } else if sess_interval_mut.renewals >= max_renewals {
                 SessResp::new(SessReqAction::DenyMaxRenewalsReached, req_sess.req_id, 0, 0)
             } else if !sess_interval_mut.renew_ok {
                 SessResp::new(
@@ -278,13 +1182,15 @@ fn handle_incoming(
                 )
             } else {
                 sess_interval_mut.timeout_start = Instant::now();
+                sess_interval_mut.duration = req_sess.duration;
                 sess_interval_mut.renew_ok = false;
                 sess_interval_mut.renewals += 1;
+                sess_interval_mut.req_id = req_sess.req_id;
                 SessResp::new(
                     SessReqAction::Renew,
                     req_sess.req_id,
                     req_sess.duration,
-                    MAX_RENEWALS - sess_interval_mut.renewals,
+                    max_renewals - sess_interval_mut.renewals,
                 )
             }
         }
@@ -292,29 +1198,48 @@ fn handle_incoming(
             SessReqAction::Grant,
             req_sess.req_id,
             req_sess.duration,
-            MAX_RENEWALS,
+            max_renewals,
         ),
     }
 }
 
-fn create_session(sessions: &Rc<RefCell<HashMap<String, SessionInterval>>>, client_addr: String) {
+/// Copies the active sessions into the server state and writes it, so they
+/// survive a restart.
+fn save_sessions(
+    sessions: &Rc<RefCell<HashMap<SessionKey, SessionInterval>>>,
+    state: &Rc<RefCell<ServerState>>,
+    metrics: &Metrics,
+) {
+    let now = unix_now();
+    let mut state_mut = state.borrow_mut();
+    state_mut.sessions = sessions
+        .borrow()
+        .iter()
+        .map(|(key, sess_interval)| sess_interval.to_saved(key, now))
+        .collect();
+    if let Err(e) = state_mut.write() {
+        error!("state file write failed: {}", e);
+        metrics.state_write_failed();
+    }
+}
+
+fn create_session(sessions: &Rc<RefCell<HashMap<SessionKey, SessionInterval>>>, sess: &Session) {
     let mut sessions_mut = sessions.borrow_mut();
     sessions_mut
-        .entry(client_addr)
-        .or_insert_with(SessionInterval::new);
+        .entry(sess.key())
+        .or_insert_with(|| SessionInterval::new(sess));
 }
 
 fn get_timeout_action(
-    sessions: &Rc<RefCell<HashMap<String, SessionInterval>>>,
+    sessions: &Rc<RefCell<HashMap<SessionKey, SessionInterval>>>,
     sess: &Session,
 ) -> TimeoutCompleteAction {
-    let client_addr = sess.req_addr.clone();
     let mut sessions_mut = sessions.borrow_mut();
-    let sess_interval = sessions_mut.entry(client_addr);
+    let sess_interval = sessions_mut.entry(sess.key());
     match sess_interval {
         Occupied(mut entry) => {
             let sess_interval_mut = entry.get_mut();
-            if sess_interval_mut.timeout_start.elapsed().as_secs() >= sess.duration {
+            if sess_interval_mut.timeout_start.elapsed().as_secs() >= sess_interval_mut.duration {
                 TimeoutCompleteAction::Revoke
             } else {
                 sess_interval_mut.renew_ok = true;
@@ -325,124 +1250,755 @@ fn get_timeout_action(
     }
 }
 
-fn grant_access(new_sess: Session, sessions: Rc<RefCell<HashMap<String, SessionInterval>>>) {
-    println!("new session for {}", new_sess.req_addr);
-
-    new_sess.handle.clone().spawn(
-        // 1: Execute the "grant" command.
-        Command::new(&new_sess.cmd)
-            .arg("grant")
-            .arg(&new_sess.req_addr)
-            .output_async(&new_sess.handle)
-            .map(|output| (output, new_sess, sessions))
-            // 2: Create a session for this client, and start a delay before the "revoke" command.
-            .and_then(move |args| {
-                let (output, new_sess, sessions) = args;
-                create_session(&sessions, new_sess.req_addr.clone());
-                print!(
-                    "start command:\n{}",
-                    str::from_utf8(&output.stdout).unwrap()
-                );
-                Timeout::new(Duration::from_secs(new_sess.duration), &new_sess.handle)
-                    .unwrap()
-                    .map(|_| (new_sess, sessions))
+/// Prints what a firewall command wrote, and how it exited if it failed.
+fn check_output(step: &str, output: &Output) -> CommandStatus {
+    info!(
+        "{} command:\n{}",
+        step,
+        String::from_utf8_lossy(&output.stdout).trim_end()
+    );
+    if !output.stderr.is_empty() {
+        warn!(
+            "{} command stderr:\n{}",
+            step,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    if !output.status.success() {
+        warn!("{} command failed: {}", step, output.status);
+    }
+    CommandStatus::from(output.status)
+}
+
+/// Runs a firewall command in a process group of its own. If it is still
+/// running after the command timeout, the whole group is killed, so a script
+/// can't leave anything behind. How long it took and how it ended go in the
+/// metrics.
+fn run_command(
+    step: &'static str,
+    mut command: Command,
+    commands: &CommandSettings,
+    metrics: &Metrics,
+    handle: &Handle,
+) -> impl Future<Item = CommandStatus, Error = ()> {
+    let started = Instant::now();
+    let metrics = metrics.clone();
+    command
+        .process_group(0)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let child = match command.spawn_async(handle) {
+        Ok(child) => child,
+        Err(e) => {
+            error!("{} command failed: {}", step, e);
+            metrics.command(step, started.elapsed(), CommandStatus::NotStarted);
+            return Either::A(future::ok(CommandStatus::NotStarted));
+        }
+    };
+    let pid = child.id();
+    let timeout = commands.timeout;
+    let handle = handle.clone();
+    Either::B(
+        child
+            .wait_with_output()
+            .select2(Timeout::new(Duration::from_secs(timeout), &handle).unwrap())
+            .then(move |res| match res {
+                Ok(Either::A((output, _))) => Ok(check_output(step, &output)),
+                Err(Either::A((e, _))) => {
+                    error!("{} command failed: {}", step, e);
+                    Ok(CommandStatus::NotStarted)
+                }
+                Ok(Either::B((_, running))) | Err(Either::B((_, running))) => {
+                    error!(
+                        "{} command timed out after {} seconds, killing process group {}",
+                        step, timeout, pid
+                    );
+                    unsafe {
+                        libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+                    }
+                    // Wait for it in the background so it doesn't linger as a zombie.
+                    handle.spawn(running.then(move |res| {
+                        if let Ok(output) = res {
+                            check_output(step, &output);
+                        }
+                        Ok(())
+                    }));
+                    Ok(CommandStatus::TimedOut)
+                }
             })
-            // 3: Continue processing after the timeout.
-            .then(|args| {
-                let (new_sess, sessions) = args.unwrap();
-                manage_session(new_sess, sessions)
+            .map(move |status| {
+                metrics.command(step, started.elapsed(), status);
+                status
             }),
-    );
+    )
 }
 
-fn renew_access(existing_sess: Session, sessions: Rc<RefCell<HashMap<String, SessionInterval>>>) {
-    println!("renew session for {}", existing_sess.req_addr);
+/// Runs the grant command, and only creates the session if it succeeds. The
+/// future gives how the command ended, so the caller can answer whoever asked.
+fn grant_access(
+    new_sess: Session,
+    sessions: Rc<RefCell<HashMap<SessionKey, SessionInterval>>>,
+    state: Rc<RefCell<ServerState>>,
+) -> impl Future<Item = CommandStatus, Error = ()> {
+    info!("new session for {}", new_sess.key());
+
+    let env = SessionInterval::new(&new_sess).env(&new_sess.key());
+    let mut command =
+        new_sess
+            .backend
+            .firewall()
+            .grant(&new_sess.req_addr, &new_sess.service, new_sess.duration);
+    command.envs(env);
+    // 1: Execute the "grant" command.
+    run_command(
+        "start",
+        command,
+        &new_sess.commands,
+        &new_sess.metrics,
+        &new_sess.handle,
+    )
+    // 2: If it worked, create a session for this client, and start a delay before the
+    // "revoke" command.
+    .map(move |status| {
+        let audit = new_sess.audit.clone();
+        let mut record = new_sess.audit_record(AuditEvent::Grant);
+        record.status = Some(status);
+        if status.success() {
+            record.action = Some(SessReqAction::Grant);
+            create_session(&sessions, &new_sess);
+            save_sessions(&sessions, &state, &new_sess.metrics);
+            let duration = new_sess.duration;
+            start_timeout(new_sess, sessions, state, duration);
+        } else {
+            warn!("no session for {}", new_sess.key());
+            record.action = Some(SessReqAction::GrantFailed);
+        }
+        audit.record(record);
+        status
+    })
+}
+
+/// Runs the renew command, if the backend has one, and restarts the session's
+/// timer once it works. The future gives whether the session was renewed. If
+/// the command fails, nobody knows when the firewall will close, so the
+/// session is revoked and the client has to ask for access again.
+fn renew_access(
+    existing_sess: Session,
+    sessions: Rc<RefCell<HashMap<SessionKey, SessionInterval>>>,
+    state: Rc<RefCell<ServerState>>,
+) -> impl Future<Item = bool, Error = ()> {
+    info!("renew session for {}", existing_sess.key());
+
+    let key = existing_sess.key();
+    let env = sessions
+        .borrow()
+        .get(&key)
+        .map(|sess_interval| sess_interval.env(&key));
+    let renew = match (
+        existing_sess.backend.firewall().renew(
+            &existing_sess.req_addr,
+            &existing_sess.service,
+            existing_sess.duration,
+        ),
+        env,
+    ) {
+        (Some(mut command), Some(env)) => {
+            command.envs(env);
+            Either::A(
+                run_command(
+                    "renew",
+                    command,
+                    &existing_sess.commands,
+                    &existing_sess.metrics,
+                    &existing_sess.handle,
+                )
+                .map(Some),
+            )
+        }
+        _ => Either::B(future::ok(None)),
+    };
+    renew.map(move |status| {
+        let mut record = existing_sess.audit_record(AuditEvent::Renew);
+        record.status = status;
+        if status.is_none_or(CommandStatus::success) {
+            record.action = Some(SessReqAction::Renew);
+            existing_sess.audit.record(record);
+            save_sessions(&sessions, &state, &existing_sess.metrics);
+            let duration = existing_sess.duration;
+            start_timeout(existing_sess, sessions, state, duration);
+            true
+        } else {
+            warn!("renew failed for {}", key);
+            record.action = Some(SessReqAction::RenewFailed);
+            existing_sess.audit.record(record);
+            revoke_access(existing_sess, sessions, state, RevokeReason::RenewFailed);
+            false
+        }
+    })
+}
+
+fn revoke_access(
+    active_sess: Session,
+    sessions: Rc<RefCell<HashMap<SessionKey, SessionInterval>>>,
+    state: Rc<RefCell<ServerState>>,
+    reason: RevokeReason,
+) {
+    // Removing the interval drops the senders for any pending timeouts, which cancels them.
+    let removed = sessions.borrow_mut().remove(&active_sess.key());
+    let mut sess_interval = match removed {
+        Some(sess_interval) => {
+            info!(
+                "removing {} after {} seconds ",
+                active_sess.key(),
+                sess_interval.session_start.elapsed().as_secs()
+            );
+            save_sessions(&sessions, &state, &active_sess.metrics);
+            sess_interval
+        }
+        None => SessionInterval::new(&active_sess),
+    };
+    // The command is told about the request that revoked the session.
+    sess_interval.req_id = active_sess.req_id;
 
-    existing_sess.handle.clone().spawn(
-        // 1: start a delay before executing "revoke" command
-        Timeout::new(
-            Duration::from_secs(existing_sess.duration),
-            &existing_sess.handle,
+    active_sess.handle.spawn(
+        run_revoke(
+            &sess_interval,
+            &active_sess.key(),
+            &active_sess.commands,
+            &active_sess.audit,
+            &active_sess.metrics,
+            reason,
+            &active_sess.handle,
         )
-        .unwrap()
-        .map(|_| (existing_sess, sessions))
-        // 2: Continue processing after the timeout.
-        .then(|args| {
-            let (existing_sess, sessions) = args.unwrap();
-            manage_session(existing_sess, sessions)
-        }),
+        .map(|_| ()),
+    );
+}
+
+/// Runs the revoke command, retrying with a doubling delay if it fails or
+/// times out, since a failed revoke leaves the firewall open. If it never
+/// succeeds, raises an alarm. Every attempt is recorded in the audit log.
+/// The future gives whether the session was revoked.
+fn run_revoke(
+    sess_interval: &SessionInterval,
+    key: &SessionKey,
+    commands: &CommandSettings,
+    audit: &AuditLog,
+    metrics: &Metrics,
+    reason: RevokeReason,
+    handle: &Handle,
+) -> impl Future<Item = bool, Error = ()> {
+    let mut record = sess_interval.audit_record(key, AuditEvent::Revoke);
+    record.reason = Some(reason);
+    let audit = audit.clone();
+    let metrics = metrics.clone();
+    let backend = sess_interval.backend.clone();
+    let env = sess_interval.env(key);
+    let key = key.clone();
+    let commands = commands.clone();
+    let handle = handle.clone();
+    future::loop_fn(1, move |attempt| {
+        let mut command = backend.firewall().revoke(&key.addr, &key.service);
+        command.envs(env.clone());
+        let key = key.clone();
+        let env = env.clone();
+        let commands = commands.clone();
+        let handle = handle.clone();
+        let mut record = record.clone();
+        let audit = audit.clone();
+        let metrics = metrics.clone();
+        run_command("stop", command, &commands, &metrics, &handle).and_then(move |status| {
+            record.status = Some(status);
+            audit.record(record.clone());
+            if status.success() {
+                Either::A(Either::A(future::ok(Loop::Break(true))))
+            } else if attempt >= REVOKE_ATTEMPTS {
+                record.event = AuditEvent::Alarm;
+                audit.record(record);
+                Either::A(Either::B(
+                    raise_alarm(&key, attempt, env, &commands, &metrics, &handle)
+                        .map(|_| Loop::Break(false)),
+                ))
+            } else {
+                let delay = REVOKE_RETRY_DELAY << (attempt - 1);
+                warn!("retrying revoke of {} in {} seconds", key, delay);
+                Either::B(
+                    Timeout::new(Duration::from_secs(delay), &handle)
+                        .unwrap()
+                        .map(move |_| Loop::Continue(attempt + 1))
+                        .map_err(|_| ()),
+                )
+            }
+        })
+    })
+}
+
+/// Reports a session that couldn't be revoked, so that someone can close it
+/// by hand. The alarm command, if there is one, is run as
+/// `<alarm_command> revoke-failed <client> <service>`, with the same
+/// environment as the revoke command.
+fn raise_alarm(
+    key: &SessionKey,
+    attempts: u32,
+    env: Vec<(&'static str, String)>,
+    commands: &CommandSettings,
+    metrics: &Metrics,
+    handle: &Handle,
+) -> impl Future<Item = (), Error = ()> {
+    error!(
+        "ALARM: revoking {} failed {} times, access is still open",
+        key, attempts
+    );
+    match commands.alarm_command {
+        Some(ref alarm_command) => {
+            let mut command = Command::new(alarm_command);
+            command
+                .arg("revoke-failed")
+                .arg(&key.addr)
+                .arg(&key.service)
+                .envs(env);
+            Either::A(run_command("alarm", command, commands, metrics, handle).map(|_| ()))
+        }
+        None => Either::B(future::ok(())),
+    }
+}
+
+fn start_timeout(
+    sess: Session,
+    sessions: Rc<RefCell<HashMap<SessionKey, SessionInterval>>>,
+    state: Rc<RefCell<ServerState>>,
+    delay: u64,
+) {
+    let (cancel_tx, cancel_rx) = oneshot::channel();
+    if let Some(sess_interval) = sessions.borrow_mut().get_mut(&sess.key()) {
+        sess_interval.timeouts.push(cancel_tx);
+    }
+
+    sess.handle.clone().spawn(
+        // 1: start a delay before executing "revoke" command, unless cancelled first.
+        Timeout::new(Duration::from_secs(delay), &sess.handle)
+            .unwrap()
+            .select2(cancel_rx)
+            // 2: Continue processing after the timeout.
+            .then(move |res| match res {
+                Ok(Either::A(_)) => manage_session(sess, sessions, state),
+                _ => {
+                    debug!("timeout for {} cancelled", sess.key());
+                    future::ok(())
+                }
+            }),
     );
 }
 
 fn manage_session(
     active_sess: Session,
-    sessions: Rc<RefCell<HashMap<String, SessionInterval>>>,
+    sessions: Rc<RefCell<HashMap<SessionKey, SessionInterval>>>,
+    state: Rc<RefCell<ServerState>>,
 ) -> futures::future::FutureResult<(), ()> {
     match get_timeout_action(&sessions, &active_sess) {
         TimeoutCompleteAction::Revoke => {
-            active_sess.handle.clone().spawn(
-                Command::new(&active_sess.cmd)
-                    .arg("revoke")
-                    .arg(&active_sess.req_addr)
-                    .output_async(&active_sess.handle)
-                    .map(|output| (output, active_sess, sessions))
-                    .then(move |args| {
-                        let (output, active_sess, sessions) = args.unwrap();
-                        {
-                            let mut sessions_mut = sessions.borrow_mut();
-                            {
-                                let sess_interval_mut =
-                                    sessions_mut.get_mut(&active_sess.req_addr).unwrap();
-                                println!(
-                                    "removing {} after {} seconds ",
-                                    active_sess.req_addr,
-                                    sess_interval_mut.session_start.elapsed().as_secs()
-                                );
-                            }
-                            sessions_mut.remove(&active_sess.req_addr);
-                        }
-
-                        print!("stop command:\n{}", str::from_utf8(&output.stdout).unwrap());
-                        future::ok(())
-                    }),
-            );
+            revoke_access(active_sess, sessions, state, RevokeReason::Expired);
             future::ok(())
         }
         TimeoutCompleteAction::Renew => future::ok(()),
         TimeoutCompleteAction::Unknown => {
-            println!("{} unknown", active_sess.req_addr);
+            warn!("{} unknown", active_sess.key());
             future::ok(())
         }
     }
 }
 
+/// Runs the setup step of every service's backend, such as creating the
+/// nftables table, when the server starts. Nothing else is running yet, so
+/// the commands are simply waited for, and a failure stops the server.
+fn set_up_firewalls(config: &ServerConfig) -> Result<(), AccessError> {
+    for (service, service_config) in &config.services {
+        for mut command in service_config.backend.firewall().setup() {
+            let output = command
+                .output()
+                .map_err(|e| AccessError::FirewallError(format!("{}: {}", service, e)))?;
+            if !output.status.success() {
+                return Err(AccessError::FirewallError(format!(
+                    "{}: {}",
+                    service,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
 fn run(
     state_filename: &str,
     key_data_filename: &str,
-    access_cmd: &str,
-    duration: u64,
+    config: ServerConfig,
+    load_config: &dyn Fn() -> Result<ServerConfig, AccessError>,
 ) -> Result<(), AccessError> {
+    set_up_firewalls(&config)?;
+
     let mut core = Core::new().map_err(AccessError::IoError)?;
     let handle = core.handle();
 
-    let codec = ServerCodec::new(
-        state_filename,
-        key_data_filename,
-        access_cmd,
-        duration,
-        &handle,
-    )?;
+    let listen_addrs: Vec<SocketAddr> = config
+        .listen_addrs
+        .iter()
+        .map(|ip_addr| SocketAddr::new(*ip_addr, config.port))
+        .collect();
+    let codec = ServerCodec::new(state_filename, key_data_filename, config, &handle)?;
+    codec.restore_sessions();
 
-    let addr: SocketAddr = format!("0.0.0.0:{}", REQ_PORT)
-        .parse()
-        .map_err(AccessError::InvalidAddr)?;
-    let sock = UdpSocket::bind(&addr, &handle).map_err(AccessError::IoError)?;
+    let mut listeners = Vec::new();
+    for addr in listen_addrs {
+        let sock = UdpSocket::bind(&addr, &handle).map_err(AccessError::IoError)?;
+        let (framed_tx, framed_rx) = sock.framed(codec.clone()).split();
+        // Responses that wait on a command come back through this channel.
+        let (responder, deferred) = mpsc::unbounded();
+        let sock_codec = codec.clone();
+        let responses = framed_rx
+            .filter_map(move |req_state| sock_codec.handle_request(req_state, &responder))
+            .select(deferred.map_err(|_| io::Error::other("responder closed")));
+        listeners.push(responses.forward(framed_tx));
+    }
 
-    let (framed_tx, framed_rx) = sock.framed(codec).split();
-    let incoming = framed_rx.forward(framed_tx);
+    let metrics_server = match codec.config.borrow().metrics_addr {
+        Some(addr) => {
+            // tokio-core's own bind goes through a version of net2 that
+            // mangles addresses on current compilers, so bind with std.
+            let listener = std::net::TcpListener::bind(addr)
+                .and_then(|listener| TcpListener::from_listener(listener, &addr, &handle))
+                .map_err(AccessError::IoError)?;
+            info!("serving metrics on http://{}/metrics", addr);
+            let codec = codec.clone();
+            let handle = handle.clone();
+            Either::A(
+                accept_logged(listener.incoming(), "metrics", &handle).for_each(
+                    move |(stream, _)| {
+                        handle.spawn(serve_metrics(stream, &codec));
+                        Ok(())
+                    },
+                ),
+            )
+        }
+        None => Either::B(future::empty()),
+    };
 
-    drop(core.run(incoming));
-    Ok(())
+    let control_socket = codec.config.borrow().control_socket.clone();
+    let control_server = match control_socket {
+        Some(ref path) => {
+            let listener = bind_control_socket(path).and_then(|listener| {
+                UnixListener::from_listener(listener, &handle).map_err(AccessError::IoError)
+            })?;
+            info!("control socket at {}", path);
+            let codec = codec.clone();
+            let handle = handle.clone();
+            Either::A(
+                accept_logged(listener.incoming(), "control", &handle).for_each(
+                    move |(stream, _)| {
+                        handle.spawn(serve_control(stream, &codec));
+                        Ok(())
+                    },
+                ),
+            )
+        }
+        None => Either::B(future::empty()),
+    };
+
+    let reload = Signal::new(SIGHUP, &handle).flatten_stream().for_each(|_| {
+        info!("reloading on SIGHUP");
+        codec.reload(key_data_filename, load_config);
+        Ok(())
+    });
+
+    let shutdown = Signal::new(SIGTERM, &handle)
+        .flatten_stream()
+        .select(Signal::new(SIGINT, &handle).flatten_stream())
+        .into_future()
+        .map_err(|(e, _)| e);
+
+    // Once a signal arrives the listeners are no longer polled, so new
+    // requests wait unanswered in the sockets while the sessions are shut
+    // down. Tasks already spawned, such as a grant in progress, still run.
+    let result = match core.run(
+        future::join_all(listeners)
+            .join(reload)
+            .join(metrics_server)
+            .join(control_server)
+            .select2(shutdown),
+    ) {
+        Ok(Either::A(_)) => Ok(()),
+        Ok(Either::B(((signal, _), _))) => {
+            info!("shutting down on signal {}", signal.unwrap_or(0));
+            shutdown_sessions(&codec, &mut core);
+            Ok(())
+        }
+        Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(AccessError::IoError(e)),
+    };
+    if let Some(path) = control_socket {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("couldn't remove {}: {}", path, e);
+        }
+    }
+    result
+}
+
+/// Creates the control socket, readable and writable only by the user the
+/// server runs as. A socket left behind by a server that is no longer
+/// running is replaced.
+fn bind_control_socket(path: &str) -> Result<net::UnixListener, AccessError> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(AccessError::FileError(format!(
+                "{} exists and isn't a socket",
+                path
+            )));
+        }
+        if net::UnixStream::connect(path).is_ok() {
+            return Err(AccessError::FileError(format!(
+                "{} is in use by another server",
+                path
+            )));
+        }
+        fs::remove_file(path)
+            .map_err(|e| AccessError::FileError(format!("couldn't remove {}: {}", path, e)))?;
+    }
+    // Set the umask as well, so the socket is never open to others.
+    let umask = unsafe { libc::umask(0o177) };
+    let bound = net::UnixListener::bind(path);
+    unsafe {
+        libc::umask(umask);
+    }
+    let listener =
+        bound.map_err(|e| AccessError::FileError(format!("couldn't bind {}: {}", path, e)))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .map_err(|e| AccessError::FileError(format!("couldn't set mode of {}: {}", path, e)))?;
+    Ok(listener)
+}
+
+/// Answers one command on the control socket. The command is read until the
+/// client shuts down its side for writing.
+fn serve_control(stream: UnixStream, codec: &ServerCodec) -> impl Future<Item = (), Error = ()> {
+    let codec = codec.clone();
+    tokio_io::io::read_to_end(stream, Vec::new())
+        .map_err(|e| debug!("control request failed: {}", e))
+        .and_then(move |(stream, msg)| {
+            let response = match ControlRequest::from_msg(&msg) {
+                Ok(request) => codec.control(request),
+                Err(e) => Box::new(future::ok(ControlResponse::Error(format!(
+                    "invalid request: {}",
+                    e
+                )))),
+            };
+            response.and_then(|response| match response.to_msg() {
+                Ok(msg) => Either::A(
+                    tokio_io::io::write_all(stream, msg)
+                        .map(|_| ())
+                        .map_err(|e| debug!("control response failed: {}", e)),
+                ),
+                Err(e) => {
+                    error!("control response encoding failed: {}", e);
+                    Either::B(future::ok(()))
+                }
+            })
+        })
+}
+
+/// Passes on the connections from `incoming`, logging a failed accept rather
+/// than ending the stream, which would stop the server. Failures such as
+/// running out of file descriptors tend to repeat, so after one the next
+/// accept waits a moment.
+fn accept_logged<S, T>(
+    incoming: S,
+    listener: &'static str,
+    handle: &Handle,
+) -> impl Stream<Item = T, Error = io::Error>
+where
+    S: Stream<Item = T, Error = io::Error>,
+{
+    let handle = handle.clone();
+    incoming
+        .then(move |accepted| match accepted {
+            Ok(conn) => Either::A(future::ok(Some(conn))),
+            Err(e) => {
+                warn!("{} accept failed: {}", listener, e);
+                Either::B(
+                    Timeout::new(Duration::from_millis(100), &handle)
+                        .unwrap()
+                        .map(|_| None),
+                )
+            }
+        })
+        .filter_map(|conn| conn)
+}
+
+/// Answers one HTTP request. Anything but `GET /metrics` gets a 404. The
+/// request is assumed to arrive in one read, which is true of Prometheus and
+/// curl.
+fn serve_metrics(stream: TcpStream, codec: &ServerCodec) -> impl Future<Item = (), Error = ()> {
+    let metrics = codec.metrics.clone();
+    let sessions = codec.sessions.clone();
+    tokio_io::io::read(stream, vec![0; 1024])
+        .and_then(move |(stream, buf, len)| {
+            let request = String::from_utf8_lossy(&buf[..len]);
+            let response = if request.starts_with("GET /metrics ") {
+                let body = metrics.render(sessions.borrow().len());
+                format!(
+                    "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                String::from("HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+            };
+            tokio_io::io::write_all(stream, response.into_bytes())
+        })
+        .map(|_| ())
+        .map_err(|e| debug!("metrics request failed: {}", e))
+}
+
+/// Revokes every open session, waiting up to the shutdown timeout for the
+/// commands to finish, and saves the state. With `keep_sessions` the sessions
+/// are only saved, to be picked up again on the next start.
+///
+/// Grants and renewals that were in progress when the signal arrived are
+/// waited for first: a grant adds a session, and a renew command that finished
+/// after the revoke would open the firewall again. A session is only dropped
+/// once its revoke works; the rest are saved to be revoked on the next start.
+fn shutdown_sessions(codec: &ServerCodec, core: &mut Core) {
+    let (keep_sessions, timeout, commands) = {
+        let config = codec.config.borrow();
+        (
+            config.keep_sessions,
+            config.shutdown_timeout,
+            CommandSettings::new(&config),
+        )
+    };
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    let revoked = Rc::new(RefCell::new(HashSet::new()));
+    let mut revoking = Vec::new();
+    loop {
+        if codec.in_progress.get() > 0 {
+            let waiting = Timeout::new(Duration::from_millis(100), &codec.handle).unwrap();
+            let finished =
+                core.run(waiting.select2(Timeout::new_at(deadline, &codec.handle).unwrap()));
+            if let Ok(Either::B(_)) = finished {
+                warn!("commands still running after {} seconds", timeout);
+                break;
+            }
+            continue;
+        }
+        let revokes: Vec<_> = if keep_sessions {
+            Vec::new()
+        } else {
+            codec
+                .sessions
+                .borrow_mut()
+                .drain()
+                .map(|(key, mut sess_interval)| {
+                    info!("revoking {}", key);
+                    // Dropping the senders cancels the pending timeouts.
+                    sess_interval.timeouts.clear();
+                    let revoke = run_revoke(
+                        &sess_interval,
+                        &key,
+                        &commands,
+                        &codec.audit,
+                        &codec.metrics,
+                        RevokeReason::Shutdown,
+                        &codec.handle,
+                    );
+                    let revoked = revoked.clone();
+                    let done = key.clone();
+                    revoking.push((key, sess_interval));
+                    revoke.map(move |ok| {
+                        if ok {
+                            revoked.borrow_mut().insert(done);
+                        }
+                    })
+                })
+                .collect()
+        };
+        if revokes.is_empty() {
+            break;
+        }
+        let finished = core.run(
+            future::join_all(revokes).select2(Timeout::new_at(deadline, &codec.handle).unwrap()),
+        );
+        if let Ok(Either::B(_)) = finished {
+            warn!("commands still running after {} seconds", timeout);
+            break;
+        }
+    }
+    if codec.in_progress.get() > 0 {
+        warn!(
+            "{} grant or renew commands still running, their sessions aren't saved",
+            codec.in_progress.get()
+        );
+    }
+
+    {
+        let revoked = revoked.borrow();
+        let mut sessions = codec.sessions.borrow_mut();
+        for (key, sess_interval) in revoking {
+            if !revoked.contains(&key) {
+                sessions.insert(key, sess_interval);
+            }
+        }
+        if keep_sessions {
+            info!("keeping {} sessions", sessions.len());
+        } else {
+            for (key, sess_interval) in sessions.iter_mut() {
+                warn!("{} isn't revoked, so the next start will revoke it", key);
+                sess_interval.revoke_pending = true;
+            }
+        }
+    }
+    save_sessions(&codec.sessions, &codec.state, &codec.metrics);
+}
+
+fn parse_arg<T>(name: &str, value: &str) -> Result<T, AccessError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value
+        .parse::<T>()
+        .map_err(|e| AccessError::InvalidConfig(format!("--{} {}: {}", name, value, e)))
+}
+
+fn get_config(matches: &ArgMatches) -> Result<ServerConfig, AccessError> {
+    let mut config = ServerConfig::read(matches.value_of("config-file").unwrap())?;
+
+    if let Some(cmd) = matches.value_of("CMD") {
+        config.command = Some(cmd.into());
+    }
+    if let Some(duration) = matches.value_of("duration") {
+        config.duration = parse_arg("duration", duration)?;
+    }
+    if let Some(max_duration) = matches.value_of("max-duration") {
+        config.max_duration = Some(parse_arg("max-duration", max_duration)?);
+    }
+    if let Some(max_renewals) = matches.value_of("max-renewals") {
+        config.max_renewals = parse_arg("max-renewals", max_renewals)?;
+    }
+    if let Some(port) = matches.value_of("port") {
+        config.port = parse_arg("port", port)?;
+    }
+    if let Some(pid_file) = matches.value_of("pid-file") {
+        config.pid_file = pid_file.into();
+    }
+    if matches.is_present("keep-sessions") {
+        config.keep_sessions = true;
+    }
+    if let Some(log_level) = matches.value_of("log-level") {
+        config.log_level = log_level.parse::<LevelFilter>().unwrap();
+    }
+    if let Some(log_target) = matches.value_of("log-target") {
+        config.log_target = log_target.parse::<LogTarget>().unwrap();
+    }
+
+    config.validate()?;
+    Ok(config)
 }
 
 fn main() {
@@ -455,8 +2011,55 @@ fn main() {
                 .empty_values(false)
                 .short("d")
                 .long("duration")
-                .default_value(DEFAULT_DURATION)
-                .help("Specify the client address"),
+                .help("Default session duration in seconds (default: 900)"),
+        )
+        .arg(
+            Arg::with_name("max-duration")
+                .empty_values(false)
+                .short("m")
+                .long("max-duration")
+                .help(
+                    "Longest session a client may request, unless set per key (default: duration)",
+                ),
+        )
+        .arg(
+            Arg::with_name("max-renewals")
+                .empty_values(false)
+                .short("r")
+                .long("max-renewals")
+                .help("Number of times a session may be renewed (default: 4)"),
+        )
+        .arg(
+            Arg::with_name("port")
+                .empty_values(false)
+                .short("p")
+                .long("port")
+                .help("UDP port to listen on (default: 7387)"),
+        )
+        .arg(
+            Arg::with_name("pid-file")
+                .empty_values(false)
+                .long("pid-file")
+                .help("Path to pid file when running as a daemon (default: /var/run/accessd.pid)"),
+        )
+        .arg(
+            Arg::with_name("keep-sessions")
+                .long("keep-sessions")
+                .help("Leave sessions open on shutdown, to be picked up by the next start"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .empty_values(false)
+                .long("log-level")
+                .possible_values(&["error", "warn", "info", "debug", "trace"])
+                .help("Least severe messages to log (default: info)"),
+        )
+        .arg(
+            Arg::with_name("log-target")
+                .empty_values(false)
+                .long("log-target")
+                .possible_values(&["stdout", "syslog", "journald"])
+                .help("Where to log (default: stdout)"),
         )
         .arg(
             Arg::with_name("state-file")
@@ -466,6 +2069,11 @@ fn main() {
                 .default_value(DEFAULT_STATE_FILENAME)
                 .help("Path to state file"),
         )
+        .arg(
+            Arg::with_name("init-state")
+                .long("init-state")
+                .help("Create a new state file and exit, before the first start"),
+        )
         .arg(
             Arg::with_name("key-data-file")
                 .empty_values(false)
@@ -474,6 +2082,14 @@ fn main() {
                 .default_value(DEFAULT_KEYDATA_FILENAME)
                 .help("Path to key data file"),
         )
+        .arg(
+            Arg::with_name("config-file")
+                .empty_values(false)
+                .short("c")
+                .long("config-file")
+                .default_value(DEFAULT_CONFIG_FILENAME)
+                .help("Path to configuration file"),
+        )
         .arg(
             Arg::with_name("foreground")
                 .short("f")
@@ -482,65 +2098,335 @@ fn main() {
         )
         .arg(
             Arg::with_name("CMD")
-                .required(true)
-                .help("Command to grant/revoke access"),
+                .help("Command to grant/revoke access for services not in the configuration file"),
         )
         .get_matches();
 
+    // Creating the state file only needs its path, so it works before there
+    // is a usable configuration.
+    if matches.is_present("init-state") {
+        let state_filename = matches.value_of("state-file").unwrap();
+        match ServerState::create(state_filename) {
+            Ok(_) => println!("created {}", state_filename),
+            Err(e) => {
+                eprintln!("failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let config = match get_config(&matches) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("failed: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = logger::init("accessd", config.log_target, config.log_level) {
+        eprintln!("failed: couldn't set up logging -- {}", e);
+        std::process::exit(1);
+    }
+
     match sodiumoxide::init() {
         Ok(()) => {
             if matches.is_present("foreground") {
                 if let Err(e) = run(
                     matches.value_of("state-file").unwrap(),
                     matches.value_of("key-data-file").unwrap(),
-                    matches.value_of("CMD").unwrap(),
-                    matches
-                        .value_of("duration")
-                        .unwrap()
-                        .parse::<u64>()
-                        .unwrap(),
+                    config,
+                    &|| get_config(&matches),
                 ) {
-                    println!("failed: {}", e);
+                    error!("failed: {}", e);
                 }
             } else {
-                match File::create(DEFAULT_DAEMON_STDOUT_FILENAME) {
-                    Ok(stdout) => match File::create(DEFAULT_DAEMON_STDERR_FILENAME) {
+                match File::create(&config.stdout_file) {
+                    Ok(stdout) => match File::create(&config.stderr_file) {
                         Ok(stderr) => {
                             let daemonize = Daemonize::new()
-                                .pid_file("/var/run/accessd.pid")
+                                .pid_file(&config.pid_file)
                                 .stdout(stdout)
                                 .stderr(stderr);
+                            let pid_file = config.pid_file.clone();
                             match daemonize.start() {
                                 Ok(_) => {
-                                    println!("accessd starting");
+                                    info!("accessd starting");
                                     if let Err(e) = run(
                                         matches.value_of("state-file").unwrap(),
                                         matches.value_of("key-data-file").unwrap(),
-                                        matches.value_of("CMD").unwrap(),
-                                        matches
-                                            .value_of("duration")
-                                            .unwrap()
-                                            .parse::<u64>()
-                                            .unwrap(),
+                                        config,
+                                        &|| get_config(&matches),
                                     ) {
-                                        println!("failed: {}", e);
+                                        error!("failed: {}", e);
+                                    }
+                                    if let Err(e) = fs::remove_file(&pid_file) {
+                                        warn!("couldn't remove {}: {}", pid_file, e);
                                     }
                                 }
                                 Err(e) => eprintln!("failed: couldn't daemonize -- {}", e),
                             };
                         }
-                        Err(e) => eprintln!(
-                            "failed: couldn't open {} -- {}",
-                            DEFAULT_DAEMON_STDERR_FILENAME, e
-                        ),
+                        Err(e) => {
+                            eprintln!("failed: couldn't open {} -- {}", config.stderr_file, e)
+                        }
                     },
-                    Err(e) => eprintln!(
-                        "failed: couldn't open {} -- {}",
-                        DEFAULT_DAEMON_STDOUT_FILENAME, e
-                    ),
+                    Err(e) => eprintln!("failed: couldn't open {} -- {}", config.stdout_file, e),
                 }
             }
         }
         Err(()) => eprintln!("failed to init crypto library"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saved_session(start: u64, expiry: u64, duration: u64) -> SavedSession {
+        SavedSession {
+            addr: String::from("192.0.2.1"),
+            service: String::from("ssh"),
+            name: String::from("cli"),
+            backend: Backend::default(),
+            start,
+            expiry,
+            duration,
+            renewals: 0,
+            req_id: 1,
+            source: None,
+            revoke_pending: false,
+        }
+    }
+
+    fn restore(
+        saved: &SavedSession,
+        now: u64,
+    ) -> (Session, Rc<RefCell<HashMap<SessionKey, SessionInterval>>>) {
+        let core = Core::new().unwrap();
+        let addr = saved.addr.parse().unwrap();
+        let sess = Session::new(
+            ReqType::TimedAccess,
+            saved.req_id,
+            saved.duration,
+            addr,
+            addr,
+            &saved.service,
+            &core.handle(),
+        );
+        let sessions = Rc::new(RefCell::new(HashMap::new()));
+        sessions
+            .borrow_mut()
+            .insert(sess.key(), SessionInterval::from_saved(saved, &sess, now));
+        (sess, sessions)
+    }
+
+    /// Runs requests through `handle_incoming`, with a state file of its own.
+    struct Server {
+        core: Core,
+        sessions: Rc<RefCell<HashMap<SessionKey, SessionInterval>>>,
+        state: ServerState,
+        config: ServerConfig,
+        req_id: u64,
+    }
+
+    impl Server {
+        fn new(name: &str) -> Server {
+            let path =
+                std::env::temp_dir().join(format!("accessd-{}-{}.yaml", std::process::id(), name));
+            let config = ServerConfig {
+                command: Some(String::from("/bin/true")),
+                ..ServerConfig::default()
+            };
+            Server {
+                core: Core::new().unwrap(),
+                sessions: Rc::new(RefCell::new(HashMap::new())),
+                state: ServerState::new_at_path(path),
+                config,
+                req_id: 0,
+            }
+        }
+
+        /// A request from `src` for `addr`, made with the key `name`.
+        fn request(&mut self, req_type: ReqType, name: &str, addr: &str, src: &str) -> Session {
+            self.req_id += 1;
+            let mut sess = Session::new(
+                req_type,
+                self.req_id,
+                60,
+                addr.parse().unwrap(),
+                src.parse().unwrap(),
+                "ssh",
+                &self.core.handle(),
+            );
+            sess.name = name.into();
+            sess
+        }
+
+        fn handle(&mut self, req_sess: &mut Session, policy: &KeyPolicy) -> SessReqAction {
+            handle_incoming(
+                &self.sessions,
+                req_sess.name.clone(),
+                req_sess,
+                &mut self.state,
+                &self.config,
+                policy,
+            )
+            .action
+        }
+
+        /// Opens a session for `name`, as a successful grant would.
+        fn open(&mut self, name: &str, addr: &str) {
+            let sess = self.request(ReqType::TimedAccess, name, addr, addr);
+            create_session(&self.sessions, &sess);
+        }
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(self.state.path());
+        }
+    }
+
+    #[test]
+    fn revoke_own_session() {
+        let mut server = Server::new("revoke-own");
+        server.open("cli", "192.0.2.1");
+        let mut req = server.request(ReqType::Revoke, "cli", "192.0.2.1", "192.0.2.1");
+        assert_eq!(
+            server.handle(&mut req, &KeyPolicy::default()),
+            SessReqAction::Revoke
+        );
+    }
+
+    #[test]
+    fn other_keys_session_is_hidden() {
+        let mut server = Server::new("revoke-other");
+        server.open("other", "192.0.2.1");
+        let mut req = server.request(ReqType::Revoke, "cli", "192.0.2.1", "198.51.100.1");
+        assert_eq!(
+            server.handle(&mut req, &KeyPolicy::default()),
+            SessReqAction::DenyNoSession
+        );
+        let req = server.request(ReqType::Status, "cli", "192.0.2.1", "198.51.100.1");
+        assert!(!session_status(&server.sessions, &req, &server.config, 4).active);
+        let req = server.request(ReqType::Status, "other", "192.0.2.1", "192.0.2.1");
+        assert!(session_status(&server.sessions, &req, &server.config, 4).active);
+    }
+
+    #[test]
+    fn disabled_key() {
+        let mut server = Server::new("disabled");
+        let policy = KeyPolicy {
+            enabled: false,
+            ..KeyPolicy::default()
+        };
+        let mut req = server.request(ReqType::TimedAccess, "cli", "192.0.2.1", "192.0.2.1");
+        assert_eq!(
+            server.handle(&mut req, &policy),
+            SessReqAction::DenyKeyDisabled
+        );
+    }
+
+    #[test]
+    fn source_not_permitted() {
+        let mut server = Server::new("source");
+        let policy = KeyPolicy {
+            source_cidrs: Some(vec!["192.0.2.0/24".parse().unwrap()]),
+            ..KeyPolicy::default()
+        };
+        let mut req = server.request(ReqType::TimedAccess, "cli", "192.0.2.1", "198.51.100.1");
+        assert_eq!(
+            server.handle(&mut req, &policy),
+            SessReqAction::DenySourceNotPermitted
+        );
+        let mut req = server.request(ReqType::TimedAccess, "cli", "192.0.2.1", "192.0.2.1");
+        assert_eq!(server.handle(&mut req, &policy), SessReqAction::Grant);
+    }
+
+    #[test]
+    fn service_not_permitted() {
+        let mut server = Server::new("service");
+        let policy = KeyPolicy {
+            services: Some(vec![String::from("http")]),
+            ..KeyPolicy::default()
+        };
+        let mut req = server.request(ReqType::TimedAccess, "cli", "192.0.2.1", "192.0.2.1");
+        assert_eq!(
+            server.handle(&mut req, &policy),
+            SessReqAction::DenyServiceNotPermitted
+        );
+    }
+
+    #[test]
+    fn target_not_permitted() {
+        let mut server = Server::new("target");
+        let policy = KeyPolicy {
+            target_cidrs: Some(vec!["192.0.2.0/24".parse().unwrap()]),
+            delegate: true,
+            ..KeyPolicy::default()
+        };
+        let mut req = server.request(ReqType::TimedAccess, "cli", "198.51.100.1", "198.51.100.1");
+        assert_eq!(
+            server.handle(&mut req, &policy),
+            SessReqAction::DenyAddressNotPermitted
+        );
+        let mut req = server.request(ReqType::TimedAccess, "cli", "192.0.2.1", "198.51.100.1");
+        assert_eq!(server.handle(&mut req, &policy), SessReqAction::Grant);
+    }
+
+    #[test]
+    fn source_must_match() {
+        let mut server = Server::new("match");
+        server.config.require_source_match = true;
+        let mut req = server.request(ReqType::TimedAccess, "cli", "192.0.2.1", "198.51.100.1");
+        assert_eq!(
+            server.handle(&mut req, &KeyPolicy::default()),
+            SessReqAction::DenyAddressNotPermitted
+        );
+        let mut req = server.request(ReqType::TimedAccess, "cli", "192.0.2.1", "192.0.2.1");
+        assert_eq!(
+            server.handle(&mut req, &KeyPolicy::default()),
+            SessReqAction::Grant
+        );
+    }
+
+    #[test]
+    fn delegate_may_name_another_address() {
+        let mut server = Server::new("delegate");
+        server.config.require_source_match = true;
+        let policy = KeyPolicy {
+            delegate: true,
+            ..KeyPolicy::default()
+        };
+        let mut req = server.request(ReqType::TimedAccess, "cli", "192.0.2.1", "198.51.100.1");
+        assert_eq!(server.handle(&mut req, &policy), SessReqAction::Grant);
+    }
+
+    // The session started long before the system did, so its instants can't
+    // be moved back to when it began.
+    const NOW: u64 = u64::MAX / 2;
+
+    #[test]
+    fn restored_session_runs_for_what_was_left() {
+        let saved = saved_session(0, NOW + 60, 3600);
+        let (sess, sessions) = restore(&saved, NOW);
+        assert_eq!(sessions.borrow()[&sess.key()].duration, 60);
+        assert_eq!(
+            sessions.borrow()[&sess.key()]
+                .to_saved(&sess.key(), NOW)
+                .expiry,
+            NOW + 60
+        );
+    }
+
+    #[test]
+    fn restored_session_is_revoked_when_its_time_is_up() {
+        // The timeout for what was left has fired.
+        let saved = saved_session(0, NOW, 3600);
+        let (sess, sessions) = restore(&saved, NOW);
+        match get_timeout_action(&sessions, &sess) {
+            TimeoutCompleteAction::Revoke => {}
+            _ => panic!("restored session wasn't revoked"),
+        }
+    }
+}
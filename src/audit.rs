@@ -0,0 +1,124 @@
+extern crate serde_json;
+
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::rc::Rc;
+
+use crate::backend::CommandStatus;
+use crate::clock::unix_now;
+use crate::err::AccessError;
+use crate::resp::SessReqAction;
+
+/// What an audit record is about. `Request` records are written when the
+/// server answers a request; the others when a firewall command finishes.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditEvent {
+    Request,
+    Grant,
+    Renew,
+    Revoke,
+    Alarm,
+}
+
+/// Why a session was closed.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RevokeReason {
+    Requested,
+    Expired,
+    KeyRemoved,
+    Shutdown,
+    Control,
+    RenewFailed,
+}
+
+/// One line of the audit log. Every record has every field, with `null` for
+/// the ones that don't apply, so the log is easy to query. The timestamp, in
+/// seconds since the Unix epoch, is set when the record is written.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AuditRecord {
+    pub timestamp: u64,
+    pub event: AuditEvent,
+    pub key: String,
+    pub source: Option<IpAddr>,
+    pub addr: Option<String>,
+    pub service: Option<String>,
+    pub req_id: u64,
+    pub action: Option<SessReqAction>,
+    pub reason: Option<RevokeReason>,
+    pub status: Option<CommandStatus>,
+    pub duration: Option<u64>,
+}
+
+impl AuditRecord {
+    pub fn new(event: AuditEvent, key: &str, req_id: u64) -> Self {
+        AuditRecord {
+            timestamp: 0,
+            event,
+            key: key.into(),
+            source: None,
+            addr: None,
+            service: None,
+            req_id,
+            action: None,
+            reason: None,
+            status: None,
+            duration: None,
+        }
+    }
+}
+
+/// An append-only file of JSON lines. Clones share the file, so reopening it,
+/// e.g. after it has been rotated, affects all of them. Without a file,
+/// nothing is recorded.
+#[derive(Clone, Default)]
+pub struct AuditLog {
+    file: Rc<RefCell<Option<File>>>,
+}
+
+impl AuditLog {
+    pub fn open(path: Option<&str>) -> Result<Self, AccessError> {
+        let audit_log = AuditLog::default();
+        audit_log.reopen(path)?;
+        Ok(audit_log)
+    }
+
+    pub fn reopen(&self, path: Option<&str>) -> Result<(), AccessError> {
+        let file = match path {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .mode(0o600)
+                    .open(path)
+                    .map_err(|e| {
+                        AccessError::FileError(format!("couldn't open {}: {}", path, e))
+                    })?,
+            ),
+            None => None,
+        };
+        *self.file.borrow_mut() = file;
+        Ok(())
+    }
+
+    /// Appends a record. Each one goes out in a single write, so lines stay
+    /// whole even if something else appends to the file.
+    pub fn record(&self, mut record: AuditRecord) {
+        if let Some(ref mut file) = *self.file.borrow_mut() {
+            record.timestamp = unix_now();
+            let written = serde_json::to_vec(&record)
+                .map_err(|e| e.to_string())
+                .and_then(|mut line| {
+                    line.push(b'\n');
+                    file.write_all(&line).map_err(|e| e.to_string())
+                });
+            if let Err(e) = written {
+                error!("audit log write failed: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,527 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus};
+
+/// Opens and closes the firewall for a client. Each step is a command for the
+/// server to run, so every backend is run, timed and logged the same way.
+pub trait Firewall {
+    /// Prepares the firewall when the server starts or reloads.
+    fn setup(&self) -> Vec<Command> {
+        Vec::new()
+    }
+
+    fn grant(&self, addr: &str, service: &str, duration: u64) -> Command;
+
+    /// Extends access for another `duration` seconds. Backends that don't
+    /// expire access on their own have nothing to do.
+    fn renew(&self, _addr: &str, _service: &str, _duration: u64) -> Option<Command> {
+        None
+    }
+
+    fn revoke(&self, addr: &str, service: &str) -> Command;
+}
+
+/// How a firewall command ended.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandStatus {
+    Exited(i32),
+    Signaled(i32),
+    TimedOut,
+    NotStarted,
+}
+
+impl CommandStatus {
+    pub fn success(self) -> bool {
+        self == CommandStatus::Exited(0)
+    }
+}
+
+impl From<ExitStatus> for CommandStatus {
+    fn from(status: ExitStatus) -> Self {
+        match status.code() {
+            Some(code) => CommandStatus::Exited(code),
+            None => CommandStatus::Signaled(status.signal().unwrap_or(0)),
+        }
+    }
+}
+
+/// How a service is opened. In the configuration this is a key in the
+/// service's map, e.g. `command: /usr/local/sbin/ipfw-ssh.sh` or
+/// `nftables: { ports: [22] }`.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Command(ExternalCommand),
+    Nftables(Nftables),
+    Ipset(Ipset),
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::Command(ExternalCommand(String::new()))
+    }
+}
+
+impl Backend {
+    pub fn firewall(&self) -> &dyn Firewall {
+        match *self {
+            Backend::Command(ref command) => command,
+            Backend::Nftables(ref nftables) => nftables,
+            Backend::Ipset(ref ipset) => ipset,
+        }
+    }
+}
+
+/// A script run as `<command> grant|revoke <client> <service>`.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct ExternalCommand(pub String);
+
+impl ExternalCommand {
+    fn command(&self, action: &str, addr: &str, service: &str) -> Command {
+        let mut command = Command::new(&self.0);
+        command.arg(action).arg(addr).arg(service);
+        command
+    }
+}
+
+impl Firewall for ExternalCommand {
+    fn grant(&self, addr: &str, service: &str, _duration: u64) -> Command {
+        self.command("grant", addr, service)
+    }
+
+    fn revoke(&self, addr: &str, service: &str) -> Command {
+        self.command("revoke", addr, service)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+fn default_nft() -> String {
+    String::from("/usr/sbin/nft")
+}
+
+fn default_table() -> String {
+    String::from("accessd")
+}
+
+/// Manages an nftables table of its own. Traffic to the ports of nftables
+/// services (the `gated` set) is dropped unless its source address and port
+/// are in `allowed4` or `allowed6`. Elements there carry a timeout, so access
+/// still closes if the server isn't around to revoke it.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Nftables {
+    pub ports: Vec<u16>,
+    #[serde(default)]
+    pub protocol: Protocol,
+    #[serde(default = "default_nft")]
+    pub nft: String,
+    #[serde(default = "default_table")]
+    pub table: String,
+}
+
+impl Nftables {
+    /// nft runs all the commands it is given in one transaction.
+    fn nft(&self, commands: &[String]) -> Command {
+        let mut command = Command::new(&self.nft);
+        command.arg(commands.join("\n"));
+        command
+    }
+
+    fn set(&self, addr: &str) -> String {
+        match addr.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => format!("inet {} allowed6", self.table),
+            _ => format!("inet {} allowed4", self.table),
+        }
+    }
+
+    fn elements(&self, addr: &str, timeout: Option<u64>) -> String {
+        let timeout = timeout
+            .map(|secs| format!(" timeout {}s", secs))
+            .unwrap_or_default();
+        let elements: Vec<String> = self
+            .ports
+            .iter()
+            .map(|port| format!("{} . {} . {}{}", addr, self.protocol, port, timeout))
+            .collect();
+        format!("{{ {} }}", elements.join(", "))
+    }
+
+    /// Adding the elements first means deleting them can't fail, even if
+    /// they already timed out.
+    fn remove(&self, addr: &str) -> Vec<String> {
+        let set = self.set(addr);
+        vec![
+            format!("add element {} {}", set, self.elements(addr, None)),
+            format!("delete element {} {}", set, self.elements(addr, None)),
+        ]
+    }
+
+    fn replace(&self, addr: &str, duration: u64) -> Command {
+        let mut commands = self.remove(addr);
+        commands.push(format!(
+            "add element {} {}",
+            self.set(addr),
+            self.elements(addr, Some(duration))
+        ));
+        self.nft(&commands)
+    }
+}
+
+impl Firewall for Nftables {
+    fn setup(&self) -> Vec<Command> {
+        let table = format!("inet {}", self.table);
+        let gated: Vec<String> = self
+            .ports
+            .iter()
+            .map(|port| format!("{} . {}", self.protocol, port))
+            .collect();
+        vec![self.nft(&[
+            format!("add table {}", table),
+            format!(
+                "add set {} allowed4 {{ type ipv4_addr . inet_proto . inet_service; flags timeout; }}",
+                table
+            ),
+            format!(
+                "add set {} allowed6 {{ type ipv6_addr . inet_proto . inet_service; flags timeout; }}",
+                table
+            ),
+            format!("add set {} gated {{ type inet_proto . inet_service; }}", table),
+            format!("add element {} gated {{ {} }}", table, gated.join(", ")),
+            format!(
+                "add chain {} input {{ type filter hook input priority 0; policy accept; }}",
+                table
+            ),
+            format!("flush chain {} input", table),
+            format!("add rule {} input ct state established,related accept", table),
+            format!(
+                "add rule {} input meta l4proto . th dport @gated ip saddr . meta l4proto . th dport != @allowed4 drop",
+                table
+            ),
+            format!(
+                "add rule {} input meta l4proto . th dport @gated ip6 saddr . meta l4proto . th dport != @allowed6 drop",
+                table
+            ),
+        ])]
+    }
+
+    fn grant(&self, addr: &str, _service: &str, duration: u64) -> Command {
+        self.replace(addr, duration)
+    }
+
+    fn renew(&self, addr: &str, _service: &str, duration: u64) -> Option<Command> {
+        Some(self.replace(addr, duration))
+    }
+
+    fn revoke(&self, addr: &str, _service: &str) -> Command {
+        self.nft(&self.remove(addr))
+    }
+}
+
+fn default_ipset() -> String {
+    String::from("/usr/sbin/ipset")
+}
+
+/// Keeps the clients of a service in a `hash:ip` ipset with per-entry
+/// timeouts. IPv6 clients go in a second set, named `set6` or, by default,
+/// the IPv4 set's name followed by "6". The iptables rules that use the sets
+/// are left to the administrator.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Ipset {
+    pub set: String,
+    #[serde(default)]
+    pub set6: Option<String>,
+    #[serde(default = "default_ipset")]
+    pub ipset: String,
+}
+
+impl Ipset {
+    fn set_for(&self, addr: &str) -> String {
+        match addr.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => self.set6(),
+            _ => self.set.clone(),
+        }
+    }
+
+    fn set6(&self) -> String {
+        self.set6
+            .clone()
+            .unwrap_or_else(|| format!("{}6", self.set))
+    }
+
+    fn ipset(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(&self.ipset);
+        command.args(args);
+        command
+    }
+}
+
+impl Firewall for Ipset {
+    /// Creates the sets, or checks that the existing ones are compatible.
+    /// accessd gives every entry its own timeout, so the default timeout only
+    /// matters for entries added by hand.
+    fn setup(&self) -> Vec<Command> {
+        vec![
+            self.ipset(&[
+                "create", "-exist", &self.set, "hash:ip", "family", "inet", "timeout", "900",
+            ]),
+            self.ipset(&[
+                "create",
+                "-exist",
+                &self.set6(),
+                "hash:ip",
+                "family",
+                "inet6",
+                "timeout",
+                "900",
+            ]),
+        ]
+    }
+
+    /// With `-exist`, adding an address that is already there resets its
+    /// timeout, so granting and renewing are the same.
+    fn grant(&self, addr: &str, _service: &str, duration: u64) -> Command {
+        self.ipset(&[
+            "add",
+            "-exist",
+            &self.set_for(addr),
+            addr,
+            "timeout",
+            &duration.to_string(),
+        ])
+    }
+
+    fn renew(&self, addr: &str, service: &str, duration: u64) -> Option<Command> {
+        Some(self.grant(addr, service, duration))
+    }
+
+    fn revoke(&self, addr: &str, _service: &str) -> Command {
+        self.ipset(&["del", "-exist", &self.set_for(addr), addr])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The program and arguments of a command.
+    fn args(command: &Command) -> Vec<String> {
+        std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn nftables(ports: Vec<u16>, protocol: Protocol) -> Nftables {
+        Nftables {
+            ports,
+            protocol,
+            nft: default_nft(),
+            table: default_table(),
+        }
+    }
+
+    /// The commands nft is given in one run.
+    fn nft_commands(command: &Command) -> Vec<String> {
+        let args = args(command);
+        assert_eq!(args.len(), 2);
+        assert_eq!(args[0], "/usr/sbin/nft");
+        args[1].lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn nftables_setup() {
+        let commands = nftables(vec![22, 2222], Protocol::Tcp).setup();
+        assert_eq!(commands.len(), 1);
+        let commands = nft_commands(&commands[0]);
+        assert_eq!(commands[0], "add table inet accessd");
+        assert!(commands.contains(&String::from(
+            "add element inet accessd gated { tcp . 22, tcp . 2222 }"
+        )));
+        assert!(commands.contains(&String::from("flush chain inet accessd input")));
+        // The chain is flushed before the rules are added again, so setting up
+        // on every reload doesn't pile up rules.
+        let flush = commands
+            .iter()
+            .position(|c| c.starts_with("flush"))
+            .unwrap();
+        assert!(commands[flush + 1..]
+            .iter()
+            .all(|c| c.starts_with("add rule")));
+    }
+
+    #[test]
+    fn nftables_grant_ipv4() {
+        let command = nftables(vec![22], Protocol::Tcp).grant("192.0.2.1", "ssh", 60);
+        assert_eq!(
+            nft_commands(&command),
+            vec![
+                "add element inet accessd allowed4 { 192.0.2.1 . tcp . 22 }",
+                "delete element inet accessd allowed4 { 192.0.2.1 . tcp . 22 }",
+                "add element inet accessd allowed4 { 192.0.2.1 . tcp . 22 timeout 60s }",
+            ]
+        );
+    }
+
+    #[test]
+    fn nftables_grant_ipv6_ports() {
+        let command = nftables(vec![80, 443], Protocol::Udp).grant("2001:db8::1", "web", 30);
+        assert_eq!(
+            nft_commands(&command),
+            vec![
+                "add element inet accessd allowed6 { 2001:db8::1 . udp . 80, 2001:db8::1 . udp . 443 }",
+                "delete element inet accessd allowed6 { 2001:db8::1 . udp . 80, 2001:db8::1 . udp . 443 }",
+                "add element inet accessd allowed6 { 2001:db8::1 . udp . 80 timeout 30s, 2001:db8::1 . udp . 443 timeout 30s }",
+            ]
+        );
+    }
+
+    #[test]
+    fn nftables_renew() {
+        let nftables = nftables(vec![22], Protocol::Tcp);
+        let renew = nftables.renew("192.0.2.1", "ssh", 90).unwrap();
+        assert_eq!(args(&renew), args(&nftables.grant("192.0.2.1", "ssh", 90)));
+    }
+
+    #[test]
+    fn nftables_revoke() {
+        let command = nftables(vec![22, 2222], Protocol::Tcp).revoke("2001:db8::1", "ssh");
+        assert_eq!(
+            nft_commands(&command),
+            vec![
+                "add element inet accessd allowed6 { 2001:db8::1 . tcp . 22, 2001:db8::1 . tcp . 2222 }",
+                "delete element inet accessd allowed6 { 2001:db8::1 . tcp . 22, 2001:db8::1 . tcp . 2222 }",
+            ]
+        );
+    }
+
+    fn ipset(set6: Option<&str>) -> Ipset {
+        Ipset {
+            set: String::from("accessd-ssh"),
+            set6: set6.map(String::from),
+            ipset: default_ipset(),
+        }
+    }
+
+    #[test]
+    fn ipset_setup() {
+        let commands = ipset(None).setup();
+        let commands: Vec<Vec<String>> = commands.iter().map(args).collect();
+        assert_eq!(
+            commands,
+            vec![
+                vec![
+                    "/usr/sbin/ipset",
+                    "create",
+                    "-exist",
+                    "accessd-ssh",
+                    "hash:ip",
+                    "family",
+                    "inet",
+                    "timeout",
+                    "900"
+                ],
+                vec![
+                    "/usr/sbin/ipset",
+                    "create",
+                    "-exist",
+                    "accessd-ssh6",
+                    "hash:ip",
+                    "family",
+                    "inet6",
+                    "timeout",
+                    "900"
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn ipset_grant() {
+        let ipset = ipset(None);
+        assert_eq!(
+            args(&ipset.grant("192.0.2.1", "ssh", 60)),
+            vec![
+                "/usr/sbin/ipset",
+                "add",
+                "-exist",
+                "accessd-ssh",
+                "192.0.2.1",
+                "timeout",
+                "60"
+            ]
+        );
+        assert_eq!(
+            args(&ipset.grant("2001:db8::1", "ssh", 60)),
+            vec![
+                "/usr/sbin/ipset",
+                "add",
+                "-exist",
+                "accessd-ssh6",
+                "2001:db8::1",
+                "timeout",
+                "60"
+            ]
+        );
+    }
+
+    #[test]
+    fn ipset_renew() {
+        let ipset = ipset(None);
+        let renew = ipset.renew("192.0.2.1", "ssh", 90).unwrap();
+        assert_eq!(args(&renew), args(&ipset.grant("192.0.2.1", "ssh", 90)));
+    }
+
+    #[test]
+    fn ipset_revoke() {
+        let ipset = ipset(None);
+        assert_eq!(
+            args(&ipset.revoke("192.0.2.1", "ssh")),
+            vec![
+                "/usr/sbin/ipset",
+                "del",
+                "-exist",
+                "accessd-ssh",
+                "192.0.2.1"
+            ]
+        );
+        assert_eq!(
+            args(&ipset.revoke("2001:db8::1", "ssh")),
+            vec![
+                "/usr/sbin/ipset",
+                "del",
+                "-exist",
+                "accessd-ssh6",
+                "2001:db8::1"
+            ]
+        );
+    }
+
+    #[test]
+    fn ipset_named_set6() {
+        let ipset = ipset(Some("clients-v6"));
+        assert_eq!(args(&ipset.setup()[1])[3], "clients-v6");
+        assert_eq!(
+            args(&ipset.grant("2001:db8::1", "ssh", 60))[3],
+            "clients-v6"
+        );
+        assert_eq!(args(&ipset.revoke("2001:db8::1", "ssh"))[3], "clients-v6");
+    }
+}
@@ -0,0 +1,226 @@
+extern crate serde_yaml;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+
+use log::LevelFilter;
+
+use crate::backend::{Backend, ExternalCommand};
+use crate::control::CONTROL_KEY;
+use crate::err::AccessError;
+use crate::logger::LogTarget;
+use crate::req::REQ_PORT;
+use crate::resp::SessReqAction;
+
+/// Where the server reads its configuration unless told otherwise.
+pub const DEFAULT_CONFIG_FILENAME: &str = "/etc/accessd.yaml";
+/// The service requested when none is named.
+pub const DEFAULT_SERVICE: &str = "ssh";
+
+#[derive(Serialize, Deserialize)]
+pub struct ServiceConfig {
+    #[serde(flatten)]
+    pub backend: Backend,
+    #[serde(default)]
+    pub keys: Option<Vec<String>>,
+}
+
+impl ServiceConfig {
+    pub fn permits(&self, name: &str) -> bool {
+        match self.keys {
+            Some(ref keys) => keys.iter().any(|key| key == name),
+            None => true,
+        }
+    }
+}
+
+/// What happens to the sessions of a key that disappears when the key data is
+/// reloaded.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RemovedKeyAction {
+    Revoke,
+    Expire,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub listen_addrs: Vec<IpAddr>,
+    pub port: u16,
+    pub command: Option<String>,
+    pub default_service: String,
+    pub duration: u64,
+    pub max_duration: Option<u64>,
+    pub max_renewals: u8,
+    pub renew_threshold: f64,
+    pub require_source_match: bool,
+    pub max_request_age: Option<u64>,
+    pub replay_window: Option<u64>,
+    pub keep_sessions: bool,
+    pub shutdown_timeout: u64,
+    pub command_timeout: u64,
+    pub alarm_command: Option<String>,
+    pub audit_log: Option<String>,
+    pub metrics_addr: Option<SocketAddr>,
+    pub control_socket: Option<String>,
+    pub log_level: LevelFilter,
+    pub log_target: LogTarget,
+    pub removed_keys: RemovedKeyAction,
+    pub accept_legacy_packets: bool,
+    pub pid_file: String,
+    pub stdout_file: String,
+    pub stderr_file: String,
+    pub services: HashMap<String, ServiceConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen_addrs: vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+            port: REQ_PORT,
+            command: None,
+            default_service: String::from(DEFAULT_SERVICE),
+            duration: 900,
+            max_duration: None,
+            max_renewals: 4,
+            renew_threshold: 0.75,
+            require_source_match: false,
+            max_request_age: None,
+            replay_window: None,
+            keep_sessions: false,
+            shutdown_timeout: 10,
+            command_timeout: 4,
+            alarm_command: None,
+            audit_log: None,
+            metrics_addr: None,
+            control_socket: None,
+            log_level: LevelFilter::Info,
+            log_target: LogTarget::Stdout,
+            removed_keys: RemovedKeyAction::Revoke,
+            accept_legacy_packets: true,
+            pid_file: String::from("/var/run/accessd.pid"),
+            stdout_file: String::from("/var/log/accessd.out"),
+            stderr_file: String::from("/var/log/accessd.err"),
+            services: HashMap::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads the server configuration. A missing file is not an error: it
+    /// yields the default configuration, in which no services are defined.
+    pub fn read(path_str: &str) -> Result<Self, AccessError> {
+        let path = Path::new(&path_str);
+
+        match File::open(path) {
+            Err(ref why) if why.kind() == ErrorKind::NotFound => Ok(ServerConfig::default()),
+            Err(why) => Err(AccessError::FileError(format!(
+                "couldn't open {} ({})",
+                path.display(),
+                why
+            ))),
+
+            Ok(mut file) => {
+                let mut yaml = String::new();
+                match file.read_to_string(&mut yaml) {
+                    Err(why) => Err(AccessError::FileError(format!(
+                        "couldn't read {}: {}",
+                        path.display(),
+                        why
+                    ))),
+                    Ok(_) => serde_yaml::from_str(&yaml).map_err(AccessError::SerializeError),
+                }
+            }
+        }
+    }
+
+    /// Checks settings that parse but make no sense. Run this after any
+    /// command line overrides have been applied.
+    pub fn validate(&self) -> Result<(), AccessError> {
+        if self.command.is_none() && self.services.is_empty() {
+            return Err(AccessError::NoCommand);
+        }
+        for (name, service_config) in &self.services {
+            if let Backend::Nftables(ref nftables) = service_config.backend {
+                if nftables.ports.is_empty() {
+                    return Err(AccessError::InvalidConfig(format!(
+                        "no ports for service {}",
+                        name
+                    )));
+                }
+            }
+        }
+        if self.listen_addrs.is_empty() {
+            return Err(AccessError::InvalidConfig(String::from(
+                "no listen addresses",
+            )));
+        }
+        if !(self.renew_threshold > 0.0 && self.renew_threshold <= 1.0) {
+            return Err(AccessError::InvalidConfig(format!(
+                "renew_threshold must be greater than 0 and at most 1, not {}",
+                self.renew_threshold
+            )));
+        }
+        if self.max_request_age == Some(0) {
+            return Err(AccessError::InvalidConfig(String::from(
+                "max_request_age must be at least 1 second",
+            )));
+        }
+        if self.replay_window == Some(0) {
+            return Err(AccessError::InvalidConfig(String::from(
+                "replay_window must be at least 1 second",
+            )));
+        }
+        // The endpoint has no authentication and shows the key names.
+        if let Some(addr) = self.metrics_addr {
+            if !addr.ip().is_loopback() {
+                return Err(AccessError::InvalidConfig(format!(
+                    "metrics_addr must be a loopback address, not {}",
+                    addr.ip()
+                )));
+            }
+        }
+        if self.command_timeout == 0 {
+            return Err(AccessError::InvalidConfig(String::from(
+                "command_timeout must be at least 1 second",
+            )));
+        }
+        Ok(())
+    }
+
+    /// The longest session a key may have when it has no limit of its own.
+    pub fn max_duration(&self) -> u64 {
+        self.max_duration.unwrap_or(self.duration)
+    }
+
+    /// Seconds into a session period after which a renewal is accepted.
+    pub fn renew_ok_after(&self, duration: u64) -> u64 {
+        ((duration as f64) * self.renew_threshold) as u64
+    }
+
+    /// Finds the backend for `service`, checking that the key called `name`
+    /// may use it. Services that aren't configured fall back to the
+    /// top-level command, if there is one. Sessions granted through the
+    /// control socket may use any service.
+    pub fn service_backend(&self, name: &str, service: &str) -> Result<Backend, SessReqAction> {
+        match self.services.get(service) {
+            Some(service_config) => {
+                if name == CONTROL_KEY || service_config.permits(name) {
+                    Ok(service_config.backend.clone())
+                } else {
+                    Err(SessReqAction::DenyServiceNotPermitted)
+                }
+            }
+            None => self
+                .command
+                .clone()
+                .map(|command| Backend::Command(ExternalCommand(command)))
+                .ok_or(SessReqAction::DenyUnknownService),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+extern crate serde_json;
+
+use std::net::IpAddr;
+
+use crate::err::AccessError;
+
+/// The key name given to sessions granted through the control socket. Key
+/// files may not use it, so these sessions are never closed for belonging to
+/// a removed key.
+pub const CONTROL_KEY: &str = "@control";
+
+/// A command sent to the server's control socket. A client connects, writes
+/// one command as JSON, shuts down its side for writing and reads the reply.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlRequest {
+    List,
+    Revoke {
+        addr: IpAddr,
+        service: String,
+    },
+    /// Adds `seconds` to the time the session has left, up to the server's
+    /// maximum duration.
+    Extend {
+        addr: IpAddr,
+        service: String,
+        seconds: u64,
+    },
+    /// Opens a session without a request from a client. Without a duration,
+    /// the server's default is used; it can't be over the maximum.
+    Grant {
+        addr: IpAddr,
+        service: String,
+        duration: Option<u64>,
+    },
+    Counters,
+}
+
+/// An open session. Times are in seconds, `start` and `expiry` since the
+/// Unix epoch.
+#[derive(Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub key: String,
+    pub addr: String,
+    pub service: String,
+    pub source: Option<IpAddr>,
+    pub start: u64,
+    pub expiry: u64,
+    pub remaining: u64,
+    pub renewals: u8,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlResponse {
+    Sessions(Vec<SessionInfo>),
+    /// The metrics in the Prometheus text format.
+    Counters(String),
+    Done(String),
+    Error(String),
+}
+
+impl ControlRequest {
+    pub fn from_msg(msg: &[u8]) -> Result<ControlRequest, AccessError> {
+        serde_json::from_slice(msg).map_err(AccessError::InvalidJson)
+    }
+
+    pub fn to_msg(&self) -> Result<Vec<u8>, AccessError> {
+        serde_json::to_vec(self).map_err(AccessError::InvalidJson)
+    }
+}
+
+impl ControlResponse {
+    pub fn from_msg(msg: &[u8]) -> Result<ControlResponse, AccessError> {
+        serde_json::from_slice(msg).map_err(AccessError::InvalidJson)
+    }
+
+    pub fn to_msg(&self) -> Result<Vec<u8>, AccessError> {
+        serde_json::to_vec(self).map_err(AccessError::InvalidJson)
+    }
+}
@@ -8,13 +8,20 @@ use sodiumoxide::crypto::box_::NONCEBYTES;
 pub enum AccessError {
     InvalidNonce,
     InvalidCiphertext,
+    UnsupportedVersion(u8),
     FileError(String),
     SerializeError(serde_yaml::Error),
     NoIpv4Addr,
     NoRemoteSet,
+    NoCommand,
+    InvalidConfig(String),
+    FirewallError(String),
     IoError(::std::io::Error),
     InvalidAddr(AddrParseError),
-    InvalidCbor(serde_cbor::error::Error)
+    InvalidCbor(serde_cbor::error::Error),
+    InvalidJson(serde_json::Error),
+    InvalidKey(String),
+    ControlError(String)
 }
 
 impl fmt::Display for AccessError {
@@ -24,13 +31,21 @@ impl fmt::Display for AccessError {
                 write!(f, "Invalid nonce data, make sure data is {} bytes", NONCEBYTES),
             AccessError::InvalidCiphertext =>
                 write!(f, "Ciphertext failed verification"),
+            AccessError::UnsupportedVersion(version) =>
+                write!(f, "unsupported protocol version {}", version),
             AccessError::FileError(ref str) => write!(f, "{}", str),
             AccessError::SerializeError(ref e) => e.fmt(f),
             AccessError::NoIpv4Addr => write!(f, "No IPv4 address found"),
             AccessError::NoRemoteSet => write!(f, "address of accessd server not set"),
+            AccessError::NoCommand => write!(f, "no command given and no services configured"),
+            AccessError::InvalidConfig(ref str) => write!(f, "invalid configuration: {}", str),
+            AccessError::FirewallError(ref str) => write!(f, "firewall setup failed: {}", str),
             AccessError::IoError(ref str) => write!(f, "{}", str),
             AccessError::InvalidAddr(ref str) => write!(f, "{}", str),
             AccessError::InvalidCbor(ref err) => write!(f, "{}", err),
+            AccessError::InvalidJson(ref err) => write!(f, "{}", err),
+            AccessError::InvalidKey(ref str) => write!(f, "invalid key: {}", str),
+            AccessError::ControlError(ref str) => write!(f, "{}", str),
         }
     }
 }
@@ -1,17 +1,25 @@
 extern crate serde_yaml;
 extern crate sodiumoxide;
 
-use std::error::Error;
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
+use std::net::IpAddr;
 use std::path::Path;
 
+use crate::control::CONTROL_KEY;
 use crate::err::AccessError;
+use crate::state;
 use data_encoding::base16;
-use serde::{Deserialize, Deserializer, Serializer};
+use ipnet::IpNet;
+use serde::de::{self, IntoDeserializer, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sodiumoxide::crypto::box_::{PublicKey, SecretKey};
 use std::collections::HashMap;
 
+/// Where the server reads its keys unless told otherwise.
+pub const DEFAULT_KEYDATA_FILENAME: &str = "/etc/accessd_keydata.yaml";
+
 #[derive(Serialize, Deserialize)]
 pub struct Keypair {
     #[serde(serialize_with = "u8vec_as_hex", deserialize_with = "seckey_from_hex")]
@@ -28,6 +36,148 @@ pub struct ClientKeyData {
     pub peer_public: PublicKey,
 }
 
+/// Limits on what a peer key may do. Every limit is optional; a key with
+/// the default policy is only bound by the server configuration. A misspelt
+/// setting is an error, rather than a limit that silently isn't there.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyPolicy {
+    #[serde(skip_serializing_if = "is_true")]
+    pub enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_duration: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_renewals: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub services: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_cidrs: Option<Vec<IpNet>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_cidrs: Option<Vec<IpNet>>,
+    #[serde(skip_serializing_if = "is_false")]
+    pub delegate: bool,
+}
+
+impl Default for KeyPolicy {
+    fn default() -> Self {
+        KeyPolicy {
+            enabled: true,
+            max_duration: None,
+            max_renewals: None,
+            services: None,
+            source_cidrs: None,
+            target_cidrs: None,
+            delegate: false,
+        }
+    }
+}
+
+impl KeyPolicy {
+    pub fn permits_service(&self, service: &str) -> bool {
+        match self.services {
+            Some(ref services) => services.iter().any(|s| s == service),
+            None => true,
+        }
+    }
+
+    pub fn permits_source(&self, addr: &IpAddr) -> bool {
+        cidrs_contain(&self.source_cidrs, addr)
+    }
+
+    pub fn permits_target(&self, addr: &IpAddr) -> bool {
+        cidrs_contain(&self.target_cidrs, addr)
+    }
+}
+
+fn cidrs_contain(cidrs: &Option<Vec<IpNet>>, addr: &IpAddr) -> bool {
+    match *cidrs {
+        Some(ref cidrs) => cidrs.iter().any(|cidr| cidr.contains(addr)),
+        None => true,
+    }
+}
+
+fn is_true(value: &bool) -> bool {
+    *value
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+pub struct PeerKey {
+    pub public: PublicKey,
+    pub policy: KeyPolicy,
+}
+
+const PEER_KEY_FIELDS: &[&str] = &[
+    "public",
+    "enabled",
+    "max_duration",
+    "max_renewals",
+    "services",
+    "source_cidrs",
+    "target_cidrs",
+    "delegate",
+];
+
+// A peer key is either a bare hex string or a map of the key and its policy.
+// The map is read field by field, rather than as an untagged enum, so that
+// an error says which field is wrong instead of that nothing matched.
+impl<'de> Deserialize<'de> for PeerKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(PeerKeyVisitor)
+    }
+}
+
+struct PeerKeyVisitor;
+
+impl<'de> Visitor<'de> for PeerKeyVisitor {
+    type Value = PeerKey;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a public key in hex, or a map of the public key and its policy")
+    }
+
+    fn visit_str<E: de::Error>(self, hex: &str) -> Result<PeerKey, E> {
+        Ok(PeerKey {
+            public: pubkey_from_hex(hex.into_deserializer())?,
+            policy: KeyPolicy::default(),
+        })
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<PeerKey, A::Error> {
+        use serde::de::Error;
+
+        let mut public = None;
+        let mut policy = KeyPolicy::default();
+        while let Some(field) = map.next_key::<String>()? {
+            match field.as_str() {
+                "public" => {
+                    let hex = map.next_value::<String>()?;
+                    let key = pubkey_from_hex(hex.as_str().into_deserializer())
+                        .map_err(|e: A::Error| A::Error::custom(format!("public: {}", e)))?;
+                    public = Some(key);
+                }
+                "enabled" => policy.enabled = map.next_value()?,
+                "max_duration" => policy.max_duration = map.next_value()?,
+                "max_renewals" => policy.max_renewals = map.next_value()?,
+                "services" => policy.services = map.next_value()?,
+                "source_cidrs" => policy.source_cidrs = map.next_value()?,
+                "target_cidrs" => policy.target_cidrs = map.next_value()?,
+                "delegate" => policy.delegate = map.next_value()?,
+                _ => return Err(A::Error::unknown_field(&field, PEER_KEY_FIELDS)),
+            }
+        }
+        match public {
+            Some(public) => Ok(PeerKey { public, policy }),
+            None => Err(A::Error::missing_field("public")),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ServerKeyData {
     #[serde(serialize_with = "u8vec_as_hex", deserialize_with = "seckey_from_hex")]
@@ -36,7 +186,7 @@ pub struct ServerKeyData {
         serialize_with = "ser_public_keys",
         deserialize_with = "de_public_keys"
     )]
-    pub peer_public_keys: HashMap<String, PublicKey>,
+    pub peer_public_keys: HashMap<String, PeerKey>,
 }
 
 pub trait KeyDataReader {
@@ -48,11 +198,11 @@ pub trait KeyDataReader {
     {
         let path = Path::new(&path_str);
 
-        match File::open(&path) {
+        match File::open(path) {
             Err(why) => Err(AccessError::FileError(format!(
                 "couldn't open {} ({})",
                 path.display(),
-                why.description()
+                why
             ))),
 
             Ok(mut file) => {
@@ -61,7 +211,7 @@ pub trait KeyDataReader {
                     Err(why) => Err(AccessError::FileError(format!(
                         "couldn't read {}: {}",
                         path.display(),
-                        why.description()
+                        why
                     ))),
                     Ok(_) => serde_yaml::from_str(&yaml).map_err(AccessError::SerializeError),
                 }
@@ -70,6 +220,22 @@ pub trait KeyDataReader {
     }
 }
 
+impl ServerKeyData {
+    /// Writes the key data to `path_str`, after checking that the new
+    /// contents load again. A failure leaves the old file as it was.
+    pub fn write(&self, path_str: &str) -> Result<(), AccessError> {
+        let yaml = serde_yaml::to_string(self).map_err(AccessError::SerializeError)?;
+        serde_yaml::from_str::<ServerKeyData>(&yaml).map_err(AccessError::SerializeError)?;
+        state::write_file(Path::new(path_str), yaml.as_bytes())
+    }
+}
+
+/// Parses a public key written in hex, the same way as in a key file.
+pub fn public_key_from_hex(hex: &str) -> Result<PublicKey, AccessError> {
+    pubkey_from_hex(hex.into_deserializer())
+        .map_err(|e: serde::de::value::Error| AccessError::InvalidKey(e.to_string()))
+}
+
 impl KeyDataReader for ClientKeyData {
     type Item = Self;
 }
@@ -82,7 +248,7 @@ where
     T: AsRef<[u8]>,
     S: Serializer,
 {
-    serializer.serialize_str(&base16::encode(&data.as_ref()))
+    serializer.serialize_str(&base16::encode(data.as_ref()))
 }
 
 fn pubkey_from_hex<'de, D>(deserializer: D) -> Result<PublicKey, D::Error>
@@ -92,7 +258,7 @@ where
     use serde::de::Error;
     String::deserialize(deserializer)
         .and_then(|string| {
-            base16::decode(&string.as_bytes()).map_err(|err| Error::custom(err.to_string()))
+            base16::decode(string.as_bytes()).map_err(|err| Error::custom(err.to_string()))
         })
         .map(|bytes| PublicKey::from_slice(&bytes))
         .and_then(|opt| opt.ok_or_else(|| Error::custom("failed to deserialize public key")))
@@ -105,33 +271,149 @@ where
     use serde::de::Error;
     String::deserialize(deserializer)
         .and_then(|string| {
-            base16::decode(&string.as_bytes()).map_err(|err| Error::custom(err.to_string()))
+            base16::decode(string.as_bytes()).map_err(|err| Error::custom(err.to_string()))
         })
         .map(|bytes| SecretKey::from_slice(&bytes))
         .and_then(|opt| opt.ok_or_else(|| Error::custom("failed to deserialize public key")))
 }
 
+// A peer key is written as a bare hex string when it has the default
+// policy, and as a map holding the key and its policy otherwise.
 fn ser_public_keys<S>(
-    peer_public: &HashMap<String, PublicKey>,
+    peer_public: &HashMap<String, PeerKey>,
     serializer: S,
 ) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
     #[derive(Serialize)]
-    struct Wrapper<'a>(#[serde(serialize_with = "u8vec_as_hex")] &'a PublicKey);
+    #[serde(untagged)]
+    enum Wrapper<'a> {
+        Key(#[serde(serialize_with = "u8vec_as_hex")] &'a PublicKey),
+        WithPolicy {
+            #[serde(serialize_with = "u8vec_as_hex")]
+            public: &'a PublicKey,
+            #[serde(flatten)]
+            policy: &'a KeyPolicy,
+        },
+    }
 
-    let map = peer_public.iter().map(|(k, v)| (k, Wrapper(v)));
+    // Sorted, so that rewriting the file doesn't shuffle it.
+    let mut peers: Vec<_> = peer_public.iter().collect();
+    peers.sort_by_key(|(k, _)| *k);
+    let map = peers.into_iter().map(|(k, v)| {
+        if v.policy == KeyPolicy::default() {
+            (k, Wrapper::Key(&v.public))
+        } else {
+            (
+                k,
+                Wrapper::WithPolicy {
+                    public: &v.public,
+                    policy: &v.policy,
+                },
+            )
+        }
+    });
     serializer.collect_map(map)
 }
 
-fn de_public_keys<'de, D>(deserializer: D) -> Result<HashMap<String, PublicKey>, D::Error>
+fn de_public_keys<'de, D>(deserializer: D) -> Result<HashMap<String, PeerKey>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    #[derive(Deserialize)]
-    struct Wrapper(#[serde(deserialize_with = "pubkey_from_hex")] PublicKey);
+    use serde::de::Error;
+
+    let v = HashMap::<String, PeerKey>::deserialize(deserializer)?;
+    if v.contains_key(CONTROL_KEY) {
+        return Err(Error::custom(format!(
+            "key name {} is reserved",
+            CONTROL_KEY
+        )));
+    }
+    Ok(v)
+}
 
-    let v = HashMap::<String, Wrapper>::deserialize(deserializer)?;
-    Ok(v.into_iter().map(|(k, Wrapper(v))| (k, v)).collect())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+    const PUBLIC: &str = "ABABABABABABABABABABABABABABABABABABABABABABABABABABABABABABABAB";
+
+    fn parse(peers: &str) -> Result<ServerKeyData, serde_yaml::Error> {
+        serde_yaml::from_str(&format!("secret: {}\npeer_public_keys:\n{}", SECRET, peers))
+    }
+
+    #[test]
+    fn bare_key() {
+        let key_data = parse(&format!("  cli: {}\n", PUBLIC)).unwrap();
+        let peer_key = &key_data.peer_public_keys["cli"];
+        assert_eq!(base16::encode(peer_key.public.as_ref()), PUBLIC);
+        assert!(peer_key.policy == KeyPolicy::default());
+    }
+
+    #[test]
+    fn key_with_policy() {
+        let key_data = parse(&format!(
+            "  cli:\n    public: {}\n    enabled: false\n    max_duration: 600\n    services: [ssh]\n    source_cidrs: [192.0.2.0/24]\n",
+            PUBLIC
+        ))
+        .unwrap();
+        let policy = &key_data.peer_public_keys["cli"].policy;
+        assert!(!policy.enabled);
+        assert_eq!(policy.max_duration, Some(600));
+        assert!(policy.permits_service("ssh"));
+        assert!(!policy.permits_service("http"));
+        assert!(policy.permits_source(&"192.0.2.1".parse().unwrap()));
+        assert!(!policy.permits_source(&"198.51.100.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn unknown_field() {
+        let e = parse(&format!(
+            "  cli:\n    public: {}\n    enabeld: false\n",
+            PUBLIC
+        ))
+        .err()
+        .unwrap();
+        assert!(e.to_string().contains("enabeld"), "{}", e);
+    }
+
+    #[test]
+    fn bad_value_names_field() {
+        let e = parse(&format!(
+            "  cli:\n    public: {}\n    max_duration: soon\n",
+            PUBLIC
+        ))
+        .err()
+        .unwrap();
+        assert!(e.to_string().contains("max_duration"), "{}", e);
+
+        let e = parse("  cli:\n    public: ABAB\n").err().unwrap();
+        assert!(e.to_string().contains("public"), "{}", e);
+    }
+
+    #[test]
+    fn missing_public_key() {
+        let e = parse("  cli:\n    enabled: false\n").err().unwrap();
+        assert!(e.to_string().contains("public"), "{}", e);
+    }
+
+    #[test]
+    fn reserved_name() {
+        assert!(parse(&format!("  \"{}\": {}\n", CONTROL_KEY, PUBLIC)).is_err());
+    }
+
+    #[test]
+    fn policy_round_trip() {
+        let key_data = parse(&format!(
+            "  cli: {}\n  other:\n    public: {}\n    delegate: true\n",
+            PUBLIC, PUBLIC
+        ))
+        .unwrap();
+        let yaml = serde_yaml::to_string(&key_data).unwrap();
+        let key_data: ServerKeyData = serde_yaml::from_str(&yaml).unwrap();
+        assert!(key_data.peer_public_keys["cli"].policy == KeyPolicy::default());
+        assert!(key_data.peer_public_keys["other"].policy.delegate);
+    }
 }
@@ -1,5 +1,8 @@
 extern crate byteorder;
 extern crate data_encoding;
+extern crate ipnet;
+#[macro_use]
+extern crate log;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
@@ -7,9 +10,17 @@ extern crate serde_cbor;
 extern crate serde_yaml;
 extern crate sodiumoxide;
 
+pub mod audit;
+pub mod backend;
+pub mod clock;
+pub mod config;
+pub mod control;
 pub mod err;
 pub mod keys;
+pub mod logger;
+pub mod metrics;
 pub mod packet;
+pub mod replay;
 pub mod req;
 pub mod resp;
 pub mod state;
@@ -0,0 +1,183 @@
+extern crate libc;
+
+use std::fmt;
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::str::FromStr;
+
+use crate::clock::unix_now;
+use crate::err::AccessError;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Where syslog listens, on Linux, FreeBSD and macOS.
+const SYSLOG_SOCKETS: &[&str] = &["/dev/log", "/var/run/log", "/var/run/syslog"];
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+/// The "daemon" facility.
+const SYSLOG_FACILITY: u8 = 3;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    #[default]
+    Stdout,
+    Syslog,
+    Journald,
+}
+
+impl FromStr for LogTarget {
+    type Err = AccessError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdout" => Ok(LogTarget::Stdout),
+            "syslog" => Ok(LogTarget::Syslog),
+            "journald" => Ok(LogTarget::Journald),
+            _ => Err(AccessError::InvalidConfig(format!(
+                "unknown log target {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for LogTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LogTarget::Stdout => write!(f, "stdout"),
+            LogTarget::Syslog => write!(f, "syslog"),
+            LogTarget::Journald => write!(f, "journald"),
+        }
+    }
+}
+
+enum Sink {
+    Stdout,
+    Syslog(UnixDatagram),
+    Journald(UnixDatagram),
+}
+
+/// Writes log records to one target. Syslog and journald add their own
+/// timestamps; on stdout the local time is written in front of each line.
+/// The level is the `log` crate's maximum level, so it can be changed while
+/// running with `log::set_max_level`.
+pub struct Logger {
+    ident: &'static str,
+    sink: Sink,
+}
+
+impl Logger {
+    fn new(ident: &'static str, target: LogTarget) -> Result<Self, AccessError> {
+        let sink = match target {
+            LogTarget::Stdout => Sink::Stdout,
+            LogTarget::Syslog => Sink::Syslog(connect(SYSLOG_SOCKETS)?),
+            LogTarget::Journald => Sink::Journald(connect(&[JOURNALD_SOCKET])?),
+        };
+        Ok(Logger { ident, sink })
+    }
+}
+
+/// Sets up logging for the program named `ident`. This can only be done once.
+pub fn init(ident: &'static str, target: LogTarget, level: LevelFilter) -> Result<(), AccessError> {
+    let logger = Logger::new(ident, target)?;
+    log::set_boxed_logger(Box::new(logger))
+        .map_err(|e| AccessError::InvalidConfig(e.to_string()))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+fn connect(paths: &[&str]) -> Result<UnixDatagram, AccessError> {
+    let socket = UnixDatagram::unbound().map_err(AccessError::IoError)?;
+    for path in paths {
+        if socket.connect(path).is_ok() {
+            return Ok(socket);
+        }
+    }
+    Err(AccessError::FileError(format!(
+        "couldn't connect to {}",
+        paths.join(" or ")
+    )))
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+fn local_time() -> String {
+    let now = unix_now() as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::localtime_r(&now, &mut tm);
+    }
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+/// Adds a field in the journal's native format. Values with newlines are
+/// sent with their length in front instead of after an `=`.
+fn journal_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend(name.as_bytes());
+    if value.contains('\n') {
+        buf.push(b'\n');
+        buf.extend(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend(value.as_bytes());
+    buf.push(b'\n');
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let msg = record.args().to_string();
+        let sent = match self.sink {
+            Sink::Stdout => {
+                let stdout = io::stdout();
+                let mut out = stdout.lock();
+                writeln!(out, "{} {:<5} {}", local_time(), record.level(), msg)
+            }
+            Sink::Syslog(ref socket) => {
+                let pri = SYSLOG_FACILITY * 8 + severity(record.level());
+                socket
+                    .send(format!("<{}>{}[{}]: {}", pri, self.ident, process::id(), msg).as_bytes())
+                    .map(|_| ())
+            }
+            Sink::Journald(ref socket) => {
+                let mut buf = Vec::new();
+                journal_field(&mut buf, "PRIORITY", &severity(record.level()).to_string());
+                journal_field(&mut buf, "SYSLOG_IDENTIFIER", self.ident);
+                journal_field(&mut buf, "MESSAGE", &msg);
+                socket.send(&buf).map(|_| ())
+            }
+        };
+        // There's nowhere better to report a lost message.
+        if let Err(e) = sent {
+            eprintln!("couldn't log \"{}\": {}", msg, e);
+        }
+    }
+
+    fn flush(&self) {
+        if let Sink::Stdout = self.sink {
+            let _ = io::stdout().flush();
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::backend::CommandStatus;
+use crate::resp::SessReqAction;
+
+#[derive(Default)]
+struct CommandStats {
+    count: u64,
+    seconds: f64,
+    failures: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    packets_received: u64,
+    decrypt_failures: u64,
+    requests: BTreeMap<String, u64>,
+    responses: BTreeMap<String, u64>,
+    commands: BTreeMap<&'static str, CommandStats>,
+    state_write_failures: u64,
+}
+
+/// Counters for the metrics endpoint. Clones share the counters. Responses
+/// are counted by action, which covers grants, renewals and every reason for
+/// a denial.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    counters: Rc<RefCell<Counters>>,
+}
+
+impl Metrics {
+    pub fn packet_received(&self) {
+        self.counters.borrow_mut().packets_received += 1;
+    }
+
+    pub fn decrypt_failed(&self) {
+        self.counters.borrow_mut().decrypt_failures += 1;
+    }
+
+    pub fn request(&self, key: &str) {
+        *self
+            .counters
+            .borrow_mut()
+            .requests
+            .entry(key.into())
+            .or_insert(0) += 1;
+    }
+
+    pub fn response(&self, action: SessReqAction) {
+        *self
+            .counters
+            .borrow_mut()
+            .responses
+            .entry(format!("{:?}", action))
+            .or_insert(0) += 1;
+    }
+
+    /// Records a firewall command that took `elapsed` to finish, or to be
+    /// given up on.
+    pub fn command(&self, step: &'static str, elapsed: Duration, status: CommandStatus) {
+        let mut counters = self.counters.borrow_mut();
+        let stats = counters.commands.entry(step).or_default();
+        stats.count += 1;
+        stats.seconds += elapsed.as_secs_f64();
+        if !status.success() {
+            stats.failures += 1;
+        }
+    }
+
+    pub fn state_write_failed(&self) {
+        self.counters.borrow_mut().state_write_failures += 1;
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn render(&self, active_sessions: usize) -> String {
+        let counters = self.counters.borrow();
+        let mut out = String::new();
+        metric(
+            &mut out,
+            "accessd_packets_received_total",
+            "counter",
+            "Packets received",
+            &[("", counters.packets_received)],
+        );
+        metric(
+            &mut out,
+            "accessd_decrypt_failures_total",
+            "counter",
+            "Packets that couldn't be decrypted with any key",
+            &[("", counters.decrypt_failures)],
+        );
+        let requests: Vec<(String, u64)> = counters
+            .requests
+            .iter()
+            .map(|(key, count)| (format!("key=\"{}\"", escape(key)), *count))
+            .collect();
+        metric(
+            &mut out,
+            "accessd_requests_total",
+            "counter",
+            "Requests by key",
+            &labelled(&requests),
+        );
+        let responses: Vec<(String, u64)> = counters
+            .responses
+            .iter()
+            .map(|(action, count)| (format!("action=\"{}\"", action), *count))
+            .collect();
+        metric(
+            &mut out,
+            "accessd_responses_total",
+            "counter",
+            "Responses by action",
+            &labelled(&responses),
+        );
+        metric(
+            &mut out,
+            "accessd_active_sessions",
+            "gauge",
+            "Open sessions",
+            &[("", active_sessions as u64)],
+        );
+        let _ = writeln!(
+            out,
+            "# HELP accessd_command_duration_seconds Time taken by firewall commands"
+        );
+        let _ = writeln!(out, "# TYPE accessd_command_duration_seconds summary");
+        for (step, stats) in &counters.commands {
+            let _ = writeln!(
+                out,
+                "accessd_command_duration_seconds_sum{{step=\"{}\"}} {}",
+                step, stats.seconds
+            );
+            let _ = writeln!(
+                out,
+                "accessd_command_duration_seconds_count{{step=\"{}\"}} {}",
+                step, stats.count
+            );
+        }
+        let failures: Vec<(String, u64)> = counters
+            .commands
+            .iter()
+            .map(|(step, stats)| (format!("step=\"{}\"", step), stats.failures))
+            .collect();
+        metric(
+            &mut out,
+            "accessd_command_failures_total",
+            "counter",
+            "Firewall commands that failed or timed out",
+            &labelled(&failures),
+        );
+        metric(
+            &mut out,
+            "accessd_state_write_failures_total",
+            "counter",
+            "Failed writes of the state file",
+            &[("", counters.state_write_failures)],
+        );
+        out
+    }
+}
+
+fn labelled(samples: &[(String, u64)]) -> Vec<(&str, u64)> {
+    samples
+        .iter()
+        .map(|(labels, value)| (labels.as_str(), *value))
+        .collect()
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, u64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
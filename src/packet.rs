@@ -1,28 +1,116 @@
 use crate::err::AccessError;
 use sodiumoxide::crypto::box_;
+use sodiumoxide::crypto::generichash;
+use sodiumoxide::randombytes::randombytes;
 
-pub fn open<'packet>(
-    packet: &'packet [u8],
-    secret_key: &box_::SecretKey,
-    public_key: &box_::PublicKey,
-) -> Result<Vec<u8>, AccessError> {
-    match box_::Nonce::from_slice(&packet[..box_::NONCEBYTES]) {
-        Some(nonce) => box_::open(
-            &packet[box_::NONCEBYTES..],
-            &nonce,
-            &public_key,
-            &secret_key,
-        )
-        .map_err(|_| AccessError::InvalidCiphertext),
-        None => Err(AccessError::InvalidNonce),
+/// Packets with a header start with the magic, then the protocol version,
+/// then a hint that lets the receiver find the sender's key without trying
+/// all of them. Every version keeps this prefix, so a peer can always tell
+/// which version was sent and answer with the right key.
+pub const MAGIC: &[u8] = b"acsd";
+/// Bump this whenever the packet layout or the request and response
+/// messages change.
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const HINTBYTES: usize = 8;
+const VERSION_OFFSET: usize = 4;
+const HEADERBYTES: usize = VERSION_OFFSET + 1 + HINTBYTES;
+const HINT_CONTEXT: &[u8] = b"accessd key hint";
+
+/// The layout of a packet. `Legacy` packets are just the nonce followed by
+/// the box; `Header` packets put the header in front of that.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Legacy,
+    Header,
+}
+
+/// The protocol version of a packet, if it has a header.
+pub fn version(packet: &[u8]) -> Option<u8> {
+    if packet.len() > VERSION_OFFSET && packet.starts_with(MAGIC) {
+        Some(packet[VERSION_OFFSET])
+    } else {
+        None
     }
 }
 
-pub fn create(
-    msg: &[u8],
-    nonce: &box_::Nonce,
-    secret_key: &box_::SecretKey,
-    public_key: &box_::PublicKey,
-) -> Vec<u8> {
-    box_::seal(&msg, nonce, &public_key, &secret_key)
+/// The nonce of a packet that has been opened in `format`. Each packet is
+/// sealed under a fresh random nonce, and changing it breaks the seal, so it
+/// identifies the packet.
+pub fn nonce(packet: &[u8], format: Format) -> &[u8] {
+    let start = match format {
+        Format::Legacy => 0,
+        Format::Header => HEADERBYTES,
+    };
+    &packet[start..start + box_::NONCEBYTES]
+}
+
+/// The keys for talking to one peer. The shared key is computed once, and the
+/// hint key is derived from it, so only the two ends can compute the hints.
+pub struct Channel {
+    shared: box_::PrecomputedKey,
+    hint_key: generichash::Digest,
+}
+
+impl Channel {
+    pub fn new(secret_key: &box_::SecretKey, public_key: &box_::PublicKey) -> Self {
+        let shared = box_::precompute(public_key, secret_key);
+        let mut state = generichash::State::new(generichash::DIGEST_MAX, None).unwrap();
+        state.update(HINT_CONTEXT).unwrap();
+        state.update(&shared[..]).unwrap();
+        Channel {
+            shared,
+            hint_key: state.finalize().unwrap(),
+        }
+    }
+
+    /// The hint is a keyed hash of the nonce, so it differs in every packet
+    /// and tells an observer nothing about which key was used.
+    fn hint(&self, nonce: &[u8]) -> Vec<u8> {
+        let mut state =
+            generichash::State::new(generichash::DIGEST_MIN, Some(&self.hint_key[..])).unwrap();
+        state.update(nonce).unwrap();
+        state.finalize().unwrap()[..HINTBYTES].to_vec()
+    }
+
+    /// Whether `packet` has a header whose hint matches this channel. The
+    /// version isn't checked.
+    pub fn is_hinted(&self, packet: &[u8]) -> bool {
+        version(packet).is_some()
+            && packet.len() >= HEADERBYTES + box_::NONCEBYTES
+            && self.hint(&packet[HEADERBYTES..HEADERBYTES + box_::NONCEBYTES])
+                == packet[VERSION_OFFSET + 1..HEADERBYTES]
+    }
+
+    pub fn open(&self, packet: &[u8], format: Format) -> Result<Vec<u8>, AccessError> {
+        let body = match format {
+            Format::Legacy => packet,
+            Format::Header => match version(packet) {
+                Some(PROTOCOL_VERSION) if packet.len() >= HEADERBYTES => &packet[HEADERBYTES..],
+                Some(PROTOCOL_VERSION) | None => return Err(AccessError::InvalidNonce),
+                Some(version) => return Err(AccessError::UnsupportedVersion(version)),
+            },
+        };
+        if body.len() < box_::NONCEBYTES {
+            return Err(AccessError::InvalidNonce);
+        }
+        match box_::Nonce::from_slice(&body[..box_::NONCEBYTES]) {
+            Some(nonce) => box_::open_precomputed(&body[box_::NONCEBYTES..], &nonce, &self.shared)
+                .map_err(|_| AccessError::InvalidCiphertext),
+            None => Err(AccessError::InvalidNonce),
+        }
+    }
+
+    /// Encrypts `msg` under a fresh nonce and lays out the packet.
+    pub fn seal(&self, msg: &[u8], format: Format) -> Vec<u8> {
+        let nonce = box_::Nonce::from_slice(&randombytes(box_::NONCEBYTES)).unwrap();
+        let mut packet = Vec::new();
+        if format == Format::Header {
+            packet.extend(MAGIC);
+            packet.push(PROTOCOL_VERSION);
+            packet.extend(self.hint(&nonce[..]));
+        }
+        packet.extend(&nonce[..]);
+        packet.extend(box_::seal_precomputed(msg, &nonce, &self.shared));
+        packet
+    }
 }
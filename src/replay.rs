@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+
+use data_encoding::base16;
+
+/// What the replay window makes of a request.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WindowCheck {
+    /// Made within the window and not seen before.
+    Fresh,
+    /// Seen before.
+    Replayed,
+    /// Made outside the window, or without a timestamp, so only its request
+    /// ID can show that it is new.
+    Outside,
+}
+
+/// The nonces of recent requests. Every packet is sealed under a fresh random
+/// nonce, so a nonce that comes back is a replay, whatever its request ID.
+///
+/// A nonce is kept until its request's timestamp is out of the window, so a
+/// request can't be judged fresh twice. That includes requests stamped in the
+/// future by a client whose clock is ahead, which are remembered even when
+/// their IDs decide. The nonces are saved with the server state, so they
+/// survive a restart. If the state is new, the nonces seen before the server
+/// started are gone, so only requests made since then are judged by the
+/// window.
+#[derive(Serialize, Deserialize, Default)]
+pub struct ReplayWindow {
+    #[serde(skip)]
+    started: u64,
+    /// Hex nonces, and when they can be forgotten.
+    seen: HashMap<String, u64>,
+}
+
+impl ReplayWindow {
+    /// Sets when the server started. Requests made earlier are left to their
+    /// request IDs.
+    pub fn set_started(&mut self, started: u64) {
+        self.started = started;
+    }
+
+    /// Checks a request made at `timestamp` and sealed under `nonce`,
+    /// remembering the nonce if the window could ever judge it fresh.
+    pub fn check(
+        &mut self,
+        nonce: &[u8],
+        timestamp: Option<u64>,
+        window: u64,
+        now: u64,
+    ) -> WindowCheck {
+        self.seen.retain(|_, forget_at| *forget_at > now);
+        let nonce = base16::encode(nonce);
+        if self.seen.contains_key(&nonce) {
+            return WindowCheck::Replayed;
+        }
+        let timestamp = match timestamp {
+            Some(timestamp) => timestamp,
+            None => return WindowCheck::Outside,
+        };
+        let fresh = timestamp >= self.started && now.abs_diff(timestamp) <= window;
+        if fresh || timestamp > now {
+            self.seen.insert(nonce, timestamp.max(now) + window + 1);
+        }
+        if fresh {
+            WindowCheck::Fresh
+        } else {
+            WindowCheck::Outside
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: u64 = 30;
+    const STARTED: u64 = 1_000_000;
+
+    fn replay_window() -> ReplayWindow {
+        let mut replay = ReplayWindow::default();
+        replay.set_started(STARTED);
+        replay
+    }
+
+    #[test]
+    fn fresh_request() {
+        let mut replay = replay_window();
+        let now = STARTED + 100;
+        assert_eq!(
+            replay.check(b"a", Some(now - 5), WINDOW, now),
+            WindowCheck::Fresh
+        );
+        assert_eq!(
+            replay.check(b"b", Some(now), WINDOW, now),
+            WindowCheck::Fresh
+        );
+    }
+
+    #[test]
+    fn replayed_request() {
+        let mut replay = replay_window();
+        let now = STARTED + 100;
+        assert_eq!(
+            replay.check(b"a", Some(now), WINDOW, now),
+            WindowCheck::Fresh
+        );
+        assert_eq!(
+            replay.check(b"a", Some(now), WINDOW, now + 10),
+            WindowCheck::Replayed
+        );
+    }
+
+    #[test]
+    fn outside_window() {
+        let mut replay = replay_window();
+        let now = STARTED + 100;
+        assert_eq!(
+            replay.check(b"a", Some(now - WINDOW - 1), WINDOW, now),
+            WindowCheck::Outside
+        );
+        assert_eq!(replay.check(b"b", None, WINDOW, now), WindowCheck::Outside);
+        // Nothing was remembered, so the IDs decide again.
+        assert_eq!(
+            replay.check(b"a", Some(now - WINDOW - 1), WINDOW, now),
+            WindowCheck::Outside
+        );
+    }
+
+    #[test]
+    fn before_start() {
+        let mut replay = replay_window();
+        assert_eq!(
+            replay.check(b"a", Some(STARTED - 1), WINDOW, STARTED + 1),
+            WindowCheck::Outside
+        );
+    }
+
+    #[test]
+    fn future_timestamp() {
+        let mut replay = replay_window();
+        let now = STARTED + 100;
+        // Within the window, it is fresh, and remembered until its timestamp
+        // has left the window.
+        assert_eq!(
+            replay.check(b"a", Some(now + WINDOW), WINDOW, now),
+            WindowCheck::Fresh
+        );
+        assert_eq!(
+            replay.check(b"a", Some(now + WINDOW), WINDOW, now + 2 * WINDOW),
+            WindowCheck::Replayed
+        );
+        // Beyond it, the ID decides, but the nonce is remembered, so the
+        // request can't pass as fresh once its time comes.
+        let later = now + 10 * WINDOW;
+        assert_eq!(
+            replay.check(b"b", Some(later), WINDOW, now),
+            WindowCheck::Outside
+        );
+        assert_eq!(
+            replay.check(b"b", Some(later), WINDOW, later),
+            WindowCheck::Replayed
+        );
+    }
+
+    #[test]
+    fn nonces_expire() {
+        let mut replay = replay_window();
+        let now = STARTED + 100;
+        assert_eq!(
+            replay.check(b"a", Some(now), WINDOW, now),
+            WindowCheck::Fresh
+        );
+        assert_eq!(replay.seen.len(), 1);
+        // Once the request is out of the window, its nonce is forgotten.
+        assert_eq!(
+            replay.check(b"a", Some(now), WINDOW, now + WINDOW + 1),
+            WindowCheck::Outside
+        );
+        assert!(replay.seen.is_empty());
+    }
+
+    #[test]
+    fn survives_restart() {
+        let mut replay = replay_window();
+        let now = STARTED + 100;
+        assert_eq!(
+            replay.check(b"a", Some(now + 10), WINDOW, now),
+            WindowCheck::Fresh
+        );
+        let saved = serde_yaml::to_string(&replay).unwrap();
+        let mut restored: ReplayWindow = serde_yaml::from_str(&saved).unwrap();
+        restored.set_started(now + 5);
+        assert_eq!(
+            restored.check(b"a", Some(now + 10), WINDOW, now + 10),
+            WindowCheck::Replayed
+        );
+    }
+}
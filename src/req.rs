@@ -1,6 +1,7 @@
 use std::fmt;
 use std::net::*;
 
+use crate::clock::unix_now;
 use crate::err::AccessError;
 use serde_cbor::de;
 use serde_cbor::ser;
@@ -11,25 +12,45 @@ pub const TIMED_ACCESS: u8 = 1;
 #[derive(Serialize, Deserialize)]
 pub enum ReqData {
     TimedAccess(IpAddr),
+    Revoke(IpAddr),
+    Status(IpAddr),
 }
 
 impl fmt::Display for ReqData {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             ReqData::TimedAccess(ip_addr) => write!(f, "timed access for {}", ip_addr),
+            ReqData::Revoke(ip_addr) => write!(f, "revoke access for {}", ip_addr),
+            ReqData::Status(ip_addr) => write!(f, "session status for {}", ip_addr),
         }
     }
 }
 
+/// A request. `timestamp` is when it was made, in seconds since the Unix
+/// epoch; it is inside the encrypted box, so it can't be changed in transit.
+/// `duration` is the session length asked for with timed access. Older
+/// clients send neither.
 #[derive(Serialize, Deserialize)]
 pub struct SessReq {
     pub req_id: u64,
+    #[serde(default)]
+    pub service: String,
     pub req_data: ReqData,
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    #[serde(default)]
+    pub duration: Option<u64>,
 }
 
 impl SessReq {
-    pub fn new(req_id: u64, req_data: ReqData) -> Self {
-        SessReq { req_id, req_data }
+    pub fn new(req_id: u64, service: &str, req_data: ReqData, duration: Option<u64>) -> Self {
+        SessReq {
+            req_id,
+            service: service.into(),
+            req_data,
+            timestamp: Some(unix_now()),
+            duration,
+        }
     }
 
     pub fn from_msg(msg: &[u8]) -> Result<SessReq, AccessError> {
@@ -43,6 +64,59 @@ impl SessReq {
 
 impl fmt::Display for SessReq {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.req_data)
+        write!(f, "{} ({})", self.req_data, self.service)?;
+        match self.duration {
+            Some(duration) => write!(f, ", {} seconds requested", duration),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A request as sent by clients from before services, timestamps and
+    /// durations were added.
+    #[derive(Serialize)]
+    enum OldReqData {
+        TimedAccess(IpAddr),
+    }
+
+    #[derive(Serialize)]
+    struct OldSessReq {
+        req_id: u64,
+        req_data: OldReqData,
+    }
+
+    #[test]
+    fn old_client_request() {
+        let old = OldSessReq {
+            req_id: 7,
+            req_data: OldReqData::TimedAccess(IpAddr::from([192, 0, 2, 1])),
+        };
+        let req = SessReq::from_msg(&ser::to_vec(&old).unwrap()).unwrap();
+        assert_eq!(req.req_id, 7);
+        assert_eq!(req.service, "");
+        assert_eq!(req.timestamp, None);
+        assert_eq!(req.duration, None);
+        match req.req_data {
+            ReqData::TimedAccess(ip_addr) => assert_eq!(ip_addr, IpAddr::from([192, 0, 2, 1])),
+            _ => panic!("wrong request type"),
+        }
+    }
+
+    #[test]
+    fn duration_round_trip() {
+        let req = SessReq::new(
+            8,
+            "ssh",
+            ReqData::TimedAccess(IpAddr::from([192, 0, 2, 1])),
+            Some(600),
+        );
+        let req = SessReq::from_msg(&req.to_msg().unwrap()).unwrap();
+        assert_eq!(req.service, "ssh");
+        assert!(req.timestamp.is_some());
+        assert_eq!(req.duration, Some(600));
     }
 }
@@ -4,7 +4,7 @@ use crate::err::AccessError;
 use serde_cbor::de;
 use serde_cbor::ser;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
 pub enum SessReqAction {
     Grant,
     Renew,
@@ -12,6 +12,18 @@ pub enum SessReqAction {
     DenyMaxRenewalsReached,
     DenyRenewAlreadyInProgress,
     DenyDuplicateRequest,
+    Revoke,
+    DenyNoSession,
+    Status,
+    DenyUnknownService,
+    DenyServiceNotPermitted,
+    DenyKeyDisabled,
+    DenySourceNotPermitted,
+    DenyAddressNotPermitted,
+    DenyUnsupportedVersion,
+    GrantFailed,
+    DenyStaleRequest,
+    RenewFailed,
 }
 
 impl fmt::Display for SessReqAction {
@@ -23,6 +35,53 @@ impl fmt::Display for SessReqAction {
             SessReqAction::DenyMaxRenewalsReached => write!(f, "max session renewals reached"),
             SessReqAction::DenyRenewAlreadyInProgress => write!(f, "renewal already requested"),
             SessReqAction::DenyDuplicateRequest => write!(f, "request is a duplicate"),
+            SessReqAction::Revoke => write!(f, "session revoked"),
+            SessReqAction::DenyNoSession => write!(f, "no active session"),
+            SessReqAction::Status => write!(f, "session status"),
+            SessReqAction::DenyUnknownService => write!(f, "service unknown"),
+            SessReqAction::DenyServiceNotPermitted => write!(f, "service not permitted for key"),
+            SessReqAction::DenyKeyDisabled => write!(f, "key is disabled"),
+            SessReqAction::DenySourceNotPermitted => {
+                write!(f, "source address not permitted for key")
+            }
+            SessReqAction::DenyAddressNotPermitted => {
+                write!(f, "requested address not permitted for key")
+            }
+            SessReqAction::DenyUnsupportedVersion => {
+                write!(f, "protocol version not supported by server")
+            }
+            SessReqAction::GrantFailed => write!(f, "firewall command failed, access not granted"),
+            SessReqAction::DenyStaleRequest => {
+                write!(f, "request is too old, or the clocks disagree")
+            }
+            SessReqAction::RenewFailed => write!(f, "firewall command failed, access revoked"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SessStatus {
+    pub active: bool,
+    pub remaining: u64,
+    pub renewals: u8,
+    pub renewals_remaining: u8,
+    pub renew_ok_in: u64,
+}
+
+impl fmt::Display for SessStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.active {
+            return write!(f, "no active session");
+        }
+        write!(
+            f,
+            "session active, {} seconds remaining. {} renewals used, {} remaining. ",
+            self.remaining, self.renewals, self.renewals_remaining
+        )?;
+        if self.renew_ok_in > 0 {
+            write!(f, "renewal ok in {} seconds.", self.renew_ok_in)
+        } else {
+            write!(f, "renewal ok now.")
         }
     }
 }
@@ -33,6 +92,8 @@ pub struct SessResp {
     pub req_id: u64,
     duration: u64,
     renewals_remaining: u8,
+    #[serde(default)]
+    status: Option<SessStatus>,
 }
 
 impl fmt::Display for SessResp {
@@ -52,6 +113,11 @@ impl fmt::Display for SessResp {
                 write!(f, "{}, renewal ok in {} seconds.", too_soon, self.duration)
             }
 
+            SessReqAction::Status => match self.status {
+                Some(ref status) => write!(f, "{}", status),
+                None => write!(f, "no status in response"),
+            },
+
             ref deny => write!(f, "{}", deny),
         }
     }
@@ -64,6 +130,17 @@ impl SessResp {
             req_id,
             duration,
             renewals_remaining,
+            status: None,
+        }
+    }
+
+    pub fn new_status(req_id: u64, status: SessStatus) -> Self {
+        SessResp {
+            action: SessReqAction::Status,
+            req_id,
+            duration: 0,
+            renewals_remaining: 0,
+            status: Some(status),
         }
     }
 
@@ -2,16 +2,24 @@ extern crate serde_yaml;
 extern crate sodiumoxide;
 
 use std::collections::HashMap;
-use std::error::Error;
-use std::fs::File;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
 use std::io::prelude::*;
+use std::io::{self, ErrorKind};
 use std::marker::Sized;
-use std::path::PathBuf;
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 
+use crate::backend::Backend;
 use crate::err::AccessError;
+use crate::replay::ReplayWindow;
 
 use serde::{Deserialize, Serialize};
 
+/// Where the server keeps its state unless told otherwise.
+pub const DEFAULT_STATE_FILENAME: &str = "/var/db/accessd_state.yaml";
+
 pub trait StateManager
 where
     Self: Serialize,
@@ -25,37 +33,21 @@ where
     fn set_path(&mut self, path: PathBuf);
 
     fn write(&self) -> Result<(), AccessError> {
-        let mut file = File::create(&self.path()).map_err(|e| {
-            AccessError::FileError(format!(
-                "couldn't create {}: {}",
-                self.path().display(),
-                e.description()
-            ))
-        })?;
-
         let yaml = serde_yaml::to_string(self).map_err(AccessError::SerializeError)?;
-
-        file.write_all(yaml.as_bytes()).map_err(|e| {
-            AccessError::FileError(format!(
-                "couldn't write to {}: {}",
-                self.path().display(),
-                e.description()
-            ))
-        })
+        write_file(self.path(), yaml.as_bytes())
     }
 
+    /// Reads the state. A missing file is an error like any other, since
+    /// starting over would accept old requests again.
     fn read(path_str: &str) -> Result<Self, AccessError> {
         let path = PathBuf::from(path_str);
 
         match File::open(&path) {
-            Err(why) => {
-                println!(
-                    "couldn't open {} ({}), so resetting nonces",
-                    path.display(),
-                    why.description()
-                );
-                Ok(Self::new_at_path(path))
-            }
+            Err(why) => Err(AccessError::FileError(format!(
+                "couldn't open {} ({})",
+                path.display(),
+                why
+            ))),
 
             Ok(mut file) => {
                 let mut yaml = String::new();
@@ -63,7 +55,7 @@ where
                     Err(why) => Err(AccessError::FileError(format!(
                         "couldn't read {}: {}",
                         path.display(),
-                        why.description()
+                        why
                     ))),
                     Ok(_) => match serde_yaml::from_str::<Self>(&yaml) {
                         Ok(mut state) => {
@@ -72,14 +64,91 @@ where
                         }
                         Err(e) => Err(AccessError::FileError(format!(
                             "couldn't parse {}: {}",
-                            path_str,
-                            e.description()
+                            path_str, e
                         ))),
                     },
                 }
             }
         }
     }
+
+    /// Reads the state, or starts a new one if the file doesn't exist yet.
+    /// A file that exists but can't be read is still an error.
+    fn read_or_new(path_str: &str) -> Result<Self, AccessError> {
+        match fs::metadata(path_str) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                warn!("{} doesn't exist, so starting a new state", path_str);
+                Ok(Self::new_at_path(PathBuf::from(path_str)))
+            }
+            _ => Self::read(path_str),
+        }
+    }
+
+    /// Writes a new, empty state. An existing file is left alone, since it
+    /// holds the request IDs that keep old requests out.
+    fn create(path_str: &str) -> Result<Self, AccessError> {
+        match fs::symlink_metadata(path_str) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                let state = Self::new_at_path(PathBuf::from(path_str));
+                state.write()?;
+                Ok(state)
+            }
+            Ok(_) => Err(AccessError::FileError(format!(
+                "{} already exists",
+                path_str
+            ))),
+            Err(e) => Err(AccessError::FileError(format!(
+                "couldn't check {}: {}",
+                path_str, e
+            ))),
+        }
+    }
+}
+
+/// Replaces the file at `path` with `data`, so that a crash or a full disk
+/// leaves either the old contents or the new ones, never part of them. The
+/// data goes to a temporary file next to it, which is synced and renamed
+/// over the old file, and then the directory is synced so the rename lasts.
+/// The file is only readable by its owner.
+pub fn write_file(path: &Path, data: &[u8]) -> Result<(), AccessError> {
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(path.file_name().unwrap_or_default());
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let written = write_synced(&tmp_path, data).and_then(|_| fs::rename(&tmp_path, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(AccessError::FileError(format!(
+            "couldn't write {}: {}",
+            path.display(),
+            e
+        )));
+    }
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| AccessError::FileError(format!("couldn't sync {}: {}", dir.display(), e)))
+}
+
+/// Writes a new file and waits for it to reach the disk. A temporary file
+/// left by an earlier failure is replaced, so it can't pass on its mode.
+fn write_synced(path: &Path, data: &[u8]) -> io::Result<()> {
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(data)?;
+    file.sync_all()
 }
 
 #[derive(Serialize, Deserialize)]
@@ -105,11 +174,38 @@ impl StateManager for ClientState {
     }
 }
 
+/// A session that was active when the server state was written. Times are
+/// seconds since the Unix epoch.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SavedSession {
+    pub addr: String,
+    pub service: String,
+    pub name: String,
+    #[serde(flatten)]
+    pub backend: Backend,
+    pub start: u64,
+    pub expiry: u64,
+    pub duration: u64,
+    pub renewals: u8,
+    #[serde(default)]
+    pub req_id: u64,
+    #[serde(default)]
+    pub source: Option<IpAddr>,
+    /// Set when the session couldn't be revoked on shutdown, so that the
+    /// next start revokes it straight away.
+    #[serde(default)]
+    pub revoke_pending: bool,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ServerState {
     #[serde(default, skip)]
     path: PathBuf,
     pub cur_req_ids: HashMap<String, u64>,
+    #[serde(default)]
+    pub sessions: Vec<SavedSession>,
+    #[serde(default)]
+    pub replay: ReplayWindow,
 }
 
 impl StateManager for ServerState {
@@ -118,6 +214,8 @@ impl StateManager for ServerState {
         Self {
             path,
             cur_req_ids: HashMap::new(),
+            sessions: Vec::new(),
+            replay: ReplayWindow::default(),
         }
     }
     fn path(&self) -> &PathBuf {
@@ -127,3 +225,118 @@ impl StateManager for ServerState {
         self.path = path
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// An empty directory for one test, removed again when it's dropped.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> TestDir {
+            let path =
+                std::env::temp_dir().join(format!("accessd-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir(&path).unwrap();
+            TestDir(path)
+        }
+
+        fn file(&self, name: &str) -> String {
+            self.0.join(name).to_string_lossy().into_owned()
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn write_replaces_file() {
+        let dir = TestDir::new("write");
+        let path = dir.0.join("state.yaml");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_file(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(mode(&path), 0o600);
+        assert!(!dir.0.join(".state.yaml.tmp").exists());
+    }
+
+    #[test]
+    fn write_replaces_stale_temp_file() {
+        let dir = TestDir::new("write-stale");
+        let path = dir.0.join("state.yaml");
+        let tmp_path = dir.0.join(".state.yaml.tmp");
+        fs::write(&tmp_path, "left over").unwrap();
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o666)).unwrap();
+
+        write_file(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(mode(&path), 0o600);
+        assert!(!tmp_path.exists());
+    }
+
+    #[test]
+    fn failed_rename_removes_temp_file() {
+        let dir = TestDir::new("write-rename");
+        // A file can't be renamed over a directory that isn't empty.
+        let path = dir.0.join("state.yaml");
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("old"), "old").unwrap();
+
+        assert!(write_file(&path, b"new").is_err());
+        assert_eq!(fs::read(path.join("old")).unwrap(), b"old");
+        assert!(!dir.0.join(".state.yaml.tmp").exists());
+    }
+
+    #[test]
+    fn failed_write_keeps_old_file() {
+        let dir = TestDir::new("write-tmp");
+        let path = dir.0.join("state.yaml");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        // The temporary file can't be created where a directory is in the way.
+        fs::create_dir(dir.0.join(".state.yaml.tmp")).unwrap();
+
+        assert!(write_file(&path, b"new").is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+        assert_eq!(mode(&path), 0o600);
+    }
+
+    #[test]
+    fn read_missing_state() {
+        let dir = TestDir::new("read-missing");
+        assert!(ServerState::read(&dir.file("state.yaml")).is_err());
+    }
+
+    #[test]
+    fn create_state() {
+        let dir = TestDir::new("create");
+        let path = dir.file("state.yaml");
+        ServerState::create(&path).unwrap();
+        let state = ServerState::read(&path).unwrap();
+        assert!(state.cur_req_ids.is_empty());
+        assert!(state.sessions.is_empty());
+    }
+
+    #[test]
+    fn create_keeps_existing_state() {
+        let dir = TestDir::new("create-existing");
+        let path = dir.file("state.yaml");
+        let mut state = ServerState::create(&path).unwrap();
+        state.cur_req_ids.insert(String::from("cli"), 42);
+        state.write().unwrap();
+
+        assert!(ServerState::create(&path).is_err());
+        assert_eq!(ServerState::read(&path).unwrap().cur_req_ids["cli"], 42);
+    }
+}
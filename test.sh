@@ -1,3 +1,4 @@
 #!/bin/sh
 
-echo $1 $2
+echo $1 $2 $3
+env | grep ^ACCESSD_ | sort